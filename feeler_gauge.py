@@ -17,6 +17,8 @@ simulator = {
     'executable': '/home/peter/github/cnc-z-perpendicularity/simulator/target/debug/simulator',
     'working_directory': '/home/peter/github/cnc-z-perpendicularity/simulator',
     'fast': True,
+    'seed': None, # draw a hidden misalignment instead of setting it below
+    'misalignment': ['EndmillOffset=normal:150:1'],
 }
 
 marlinPort = "/dev/serial/by-id/usb-Arduino__www.arduino.cc__0042_85531303231351E0E181-if00"
@@ -70,7 +72,10 @@ marlin.connect(marlinPort, marlinBaudrate)
 if useSimulator:
     startX = 500 + math.cos(math.radians(approxAngle)) * approxLen - (feelerGaugeLength / 2.0 - 0.0)
     startY = 250 + math.sin(math.radians(approxAngle)) * approxLen
-    if 0:
+    if simulator['seed'] is not None:
+        marlin.send('M801 R%d' % (approxAngle - 180))
+        marlin.send('G1 X%d Y%d' % (startX, startY))
+    elif 0:
         marlin.send('M800 A0.5  B0.25')
         marlin.send('M801 A1    B0.5   R%d' % (approxAngle - 180))
         marlin.send('M802 A0.5  B1     O%f' % approxLen)
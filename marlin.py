@@ -14,6 +14,10 @@ class Marlin:
             command = [self.simulator['executable'], "--no-keyboard"]
             if self.simulator['fast']:
                 command += ["--fast"]
+            if self.simulator.get('seed') is not None:
+                command += ["--seed", str(self.simulator['seed'])]
+                for misalignment in self.simulator.get('misalignment', []):
+                    command += ["--misalignment", misalignment]
             self.conn = subprocess.Popen(command,
                             cwd=self.simulator['working_directory'],
                             stdin=subprocess.PIPE,
@@ -1,15 +1,88 @@
 use crate::chain::{Transform, Vec3};
-use crate::probe::Probe;
+use crate::mesh_file::MeshFile;
+use crate::probe::{Material, Probe, ProbeInput, PROBE_NET};
 
 use kiss3d::scene::SceneNode;
 use kiss3d::window::Window;
 use std::path::Path;
 use ncollide3d::shape::ShapeHandle;
-use na::{Translation3, UnitQuaternion};
+use na::{Point3, Translation3, UnitQuaternion};
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
 
 pub trait CalibrationObject {
     fn get_probe(&self) -> Probe;
     fn render(&mut self);
+
+    // The first input is used by G28 and G38 unless another one is selected
+    fn get_inputs(&self) -> Vec<ProbeInput> {
+        vec![ProbeInput::new("z_min", vec![PROBE_NET])]
+    }
+}
+
+// The calibration object on the spoilboard and its settings
+pub enum ObjectDefinition {
+    FeelerGauge { pos: Transform, size: Vec3, tilt: UnitQuaternion<f64> },
+    TwoWires { pos: Transform, wire_diameter: f64 },
+    GaugeBlock { pos: Transform, size: Vec3 },
+    ReferenceSphere { pos: Transform, diameter: f64, stem_length: f64 },
+    RingGauge { pos: Transform, inner_diameter: f64, outer_diameter: f64, height: f64 },
+    Spoilboard { size: (f64, f64), warp: f64, twist: f64, noise: f64, seed: u64 },
+}
+
+impl ObjectDefinition {
+    pub fn build(&self, window: &mut Window, resources_dir: &Path) -> Box<dyn CalibrationObject> {
+        match self {
+            ObjectDefinition::FeelerGauge { pos, size, tilt } => FeelerGauge::new(window, resources_dir, pos, size, tilt),
+            ObjectDefinition::TwoWires { pos, wire_diameter } => TwoWires::new(window, resources_dir, pos, *wire_diameter),
+            ObjectDefinition::GaugeBlock { pos, size } => GaugeBlock::new(window, pos, size),
+            ObjectDefinition::ReferenceSphere { pos, diameter, stem_length } => ReferenceSphere::new(window, pos, *diameter, *stem_length),
+            ObjectDefinition::RingGauge { pos, inner_diameter, outer_diameter, height } => RingGauge::new(window, pos, *inner_diameter, *outer_diameter, *height),
+            ObjectDefinition::Spoilboard { size, warp, twist, noise, seed } => Spoilboard::new(window, *size, *warp, *twist, *noise, *seed),
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        match self {
+            ObjectDefinition::RingGauge { inner_diameter, outer_diameter, height, .. } => {
+                if *inner_diameter <= 0.0 || *height <= 0.0 {
+                    Err("the ring gauge needs a positive inner diameter and height".to_string())
+                } else if inner_diameter >= outer_diameter {
+                    Err(format!("the inner diameter of the ring gauge ({}mm) must be smaller than its outer diameter ({}mm)",
+                        inner_diameter * 1000.0, outer_diameter * 1000.0))
+                } else {
+                    Ok(())
+                }
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // The spoilboard is fixed to the machine, so it has no pose
+    pub fn get_pose_mut(&mut self) -> Option<&mut Transform> {
+        match self {
+            ObjectDefinition::FeelerGauge { pos, .. }
+            | ObjectDefinition::TwoWires { pos, .. }
+            | ObjectDefinition::GaugeBlock { pos, .. }
+            | ObjectDefinition::ReferenceSphere { pos, .. }
+            | ObjectDefinition::RingGauge { pos, .. } => Some(pos),
+            ObjectDefinition::Spoilboard { .. } => None,
+        }
+    }
+}
+
+// Rotated by `rotation` around Z, then tilted around X and Y, and placed at `position`
+pub fn get_pose(position: &Vec3, rotation: f64, tilt_x: f64, tilt_y: f64) -> Transform {
+    Transform::from_parts(
+        Translation3::from(*position),
+        UnitQuaternion::from_axis_angle(&Vec3::x_axis(), tilt_x) * UnitQuaternion::from_axis_angle(&Vec3::y_axis(), tilt_y) * UnitQuaternion::from_axis_angle(&Vec3::z_axis(), rotation)
+    )
+}
+
+// The printed parts use the same meshes for rendering and collisions, in mm
+fn load_shape(file: &Path) -> ShapeHandle<f64> {
+    let mesh = MeshFile::load(file, 0.001).unwrap_or_else(|e| panic!("Could not load mesh: {}", e));
+    Probe::get_mesh_shape(&mesh, &Transform::identity())
 }
 
 pub struct FeelerGauge {
@@ -18,9 +91,46 @@ pub struct FeelerGauge {
     gauge_holder: SceneNode,
     gauge: SceneNode,
 
+    gauge_holder_shape: ShapeHandle<f64>,
     gauge_shape: ShapeHandle<f64>,
 }
 
+// A steel block like a 1-2-3 block, standing on the spoilboard
+pub struct GaugeBlock {
+    pos: Transform,
+
+    block: SceneNode,
+
+    block_shape: ShapeHandle<f64>,
+}
+
+// A precision sphere on a vertical stem
+pub struct ReferenceSphere {
+    pos: Transform,
+
+    sphere: SceneNode,
+    stem: SceneNode,
+
+    sphere_shape: ShapeHandle<f64>,
+    stem_shape: ShapeHandle<f64>,
+}
+
+// A ring lying on the spoilboard, probed on the inside of its bore
+pub struct RingGauge {
+    pos: Transform,
+
+    ring: SceneNode,
+
+    ring_shape: ShapeHandle<f64>,
+}
+
+// The top of the spoilboard, from the origin to `size`. It is flat at z = 0 unless it is warped.
+pub struct Spoilboard {
+    board: SceneNode,
+
+    board_shape: ShapeHandle<f64>,
+}
+
 pub struct TwoWires {
     pos: Transform,
 
@@ -31,6 +141,7 @@ pub struct TwoWires {
     bolt_x: SceneNode,
     bolt_y: SceneNode,
 
+    plastic_shape: ShapeHandle<f64>,
     wire_x_shape: ShapeHandle<f64>,
     wire_y_shape: ShapeHandle<f64>,
     bolt_c_shape: ShapeHandle<f64>,
@@ -39,23 +150,25 @@ pub struct TwoWires {
 }
 
 impl FeelerGauge {
-    #[allow(dead_code)]
-    pub fn new(window: &mut Window, resources_dir: &Path) -> Box<dyn CalibrationObject> {
+    pub fn get_default_position() -> Vec3 {
+        Vec3::new(0.50, 0.25, 0.0)
+    }
+
+    // `size` is the length, width and thickness of the gauge. It lies on the holder, but can be
+    // rotated and tilted on it by `tilt`.
+    pub fn new(window: &mut Window, resources_dir: &Path, pos: &Transform, size: &Vec3, tilt: &UnitQuaternion<f64>) -> Box<dyn CalibrationObject> {
         let mm = na::Vector3::new(0.001, 0.001, 0.001);
-        let x = 89.0 / 1000.0;
-        let y = 13.0 / 1000.0;
-        let z =  0.8 / 1000.0;
 
-        let (mesh, shape) = Probe::get_box_shape(x, y, z, &Transform::from_parts(
-            Translation3::new(0.0, 0.0, 0.025),
-            //UnitQuaternion::from_axis_angle(&Vec3::z_axis(), 2.0_f64.to_radians()) * UnitQuaternion::from_axis_angle(&Vec3::y_axis(), 1.0_f64.to_radians())  * UnitQuaternion::from_axis_angle(&Vec3::x_axis(), 1.5_f64.to_radians())
-            UnitQuaternion::identity()
+        let (mesh, shape) = Probe::get_box_shape(size.x, size.y, size.z, &Transform::from_parts(
+            Translation3::new(0.0, 0.0, 0.0246 + size.z / 2.0),
+            *tilt
         ));
 
         let mut object = FeelerGauge {
-            pos: Transform::translation(0.50, 0.25, 0.0),
+            pos: *pos,
             gauge_holder: window.add_obj(&resources_dir.join("gauge-holder.obj"), resources_dir, mm),
             gauge: window.add_trimesh(mesh, na::Vector3::from_element(1.0_f32)),
+            gauge_holder_shape: load_shape(&resources_dir.join("gauge-holder.obj")),
             gauge_shape: shape,
         };
 
@@ -69,7 +182,8 @@ impl FeelerGauge {
 impl CalibrationObject for FeelerGauge {
     fn get_probe(&self) -> Probe {
         Probe::new(vec![
-            (self.pos, self.gauge_shape.clone()),
+            ("holder", Material::Insulating, self.pos, self.gauge_holder_shape.clone()),
+            ("gauge", Material::Conductive(Some("gauge")), self.pos, self.gauge_shape.clone()),
         ])
     }
 
@@ -80,10 +194,12 @@ impl CalibrationObject for FeelerGauge {
 }
 
 impl TwoWires {
-    #[allow(dead_code)]
-    pub fn new(window: &mut Window, resources_dir: &Path) -> Box<dyn CalibrationObject> {
+    pub fn get_default_position() -> Vec3 {
+        Vec3::new(0.52, 0.26, 0.0)
+    }
+
+    pub fn new(window: &mut Window, resources_dir: &Path, pos: &Transform, diameter: f64) -> Box<dyn CalibrationObject> {
         let mm = na::Vector3::new(0.001, 0.001, 0.001);
-        let diameter = 0.1 / 1000.0;
         let length = 0.040;
 
         let (wire_x_mesh, wire_x_shape) = Probe::get_cylinder_shape(diameter, length, &Transform::from_parts(
@@ -113,13 +229,14 @@ impl TwoWires {
         ));
 
         let mut object = TwoWires {
-            pos: Transform::translation(0.52, 0.26, 0.0),
+            pos: *pos,
             plastic: window.add_obj(&resources_dir.join("calibration-object-plastic.obj"), resources_dir, mm),
             wire_x: window.add_trimesh(wire_x_mesh, na::Vector3::from_element(1.0_f32)),
             wire_y: window.add_trimesh(wire_y_mesh, na::Vector3::from_element(1.0_f32)),
             bolt_c: window.add_trimesh(bolt_c_mesh, na::Vector3::from_element(1.0_f32)),
             bolt_x: window.add_trimesh(bolt_x_mesh, na::Vector3::from_element(1.0_f32)),
             bolt_y: window.add_trimesh(bolt_y_mesh, na::Vector3::from_element(1.0_f32)),
+            plastic_shape: load_shape(&resources_dir.join("calibration-object-plastic.obj")),
             wire_x_shape,
             wire_y_shape,
             bolt_c_shape,
@@ -141,11 +258,13 @@ impl TwoWires {
 impl CalibrationObject for TwoWires {
     fn get_probe(&self) -> Probe {
         Probe::new(vec![
-            (self.pos, self.wire_x_shape.clone()),
-            (self.pos, self.wire_y_shape.clone()),
-            (self.pos, self.bolt_c_shape.clone()),
-            (self.pos, self.bolt_x_shape.clone()),
-            (self.pos, self.bolt_y_shape.clone()),
+            ("plastic", Material::Insulating, self.pos, self.plastic_shape.clone()),
+            // the Y wire is insulated from the corner bolt, so the wires are separate nets
+            ("wire_x", Material::Conductive(Some("wire_x")), self.pos, self.wire_x_shape.clone()),
+            ("wire_y", Material::Conductive(Some("wire_y")), self.pos, self.wire_y_shape.clone()),
+            ("bolt_c", Material::Conductive(Some("wire_x")), self.pos, self.bolt_c_shape.clone()),
+            ("bolt_x", Material::Conductive(Some("wire_x")), self.pos, self.bolt_x_shape.clone()),
+            ("bolt_y", Material::Conductive(Some("wire_y")), self.pos, self.bolt_y_shape.clone()),
         ])
     }
 
@@ -157,4 +276,215 @@ impl CalibrationObject for TwoWires {
         self.bolt_x.set_local_transformation(na::convert(self.pos));
         self.bolt_y.set_local_transformation(na::convert(self.pos));
     }
+
+    // z_min is triggered by both wires, like a single probe input
+    fn get_inputs(&self) -> Vec<ProbeInput> {
+        vec![
+            ProbeInput::new("z_min", vec![PROBE_NET]),
+            ProbeInput::new("wire_x", vec!["wire_x"]),
+            ProbeInput::new("wire_y", vec!["wire_y"]),
+        ]
+    }
+}
+
+impl GaugeBlock {
+    pub fn get_default_position() -> Vec3 {
+        Vec3::new(0.50, 0.25, 0.0)
+    }
+
+    // `size` is along X, Y and Z
+    pub fn new(window: &mut Window, pos: &Transform, size: &Vec3) -> Box<dyn CalibrationObject> {
+        let (mesh, shape) = Probe::get_box_shape(size.x, size.y, size.z, &Transform::translation(0.0, 0.0, size.z / 2.0));
+
+        let mut object = GaugeBlock {
+            pos: *pos,
+            block: window.add_trimesh(mesh, na::Vector3::from_element(1.0_f32)),
+            block_shape: shape,
+        };
+
+        object.block.set_color(0.7, 0.7, 0.75);
+
+        Box::new(object)
+    }
+}
+
+impl CalibrationObject for GaugeBlock {
+    // all faces are conductive, the probe is clipped to the block
+    fn get_probe(&self) -> Probe {
+        Probe::new(vec![
+            ("block", Material::Conductive(Some("block")), self.pos, self.block_shape.clone()),
+        ])
+    }
+
+    fn render(&mut self) {
+        self.block.set_local_transformation(na::convert(self.pos));
+    }
+}
+
+impl ReferenceSphere {
+    pub fn get_default_position() -> Vec3 {
+        Vec3::new(0.50, 0.25, 0.0)
+    }
+
+    pub fn new(window: &mut Window, pos: &Transform, diameter: f64, stem_length: f64) -> Box<dyn CalibrationObject> {
+        let stem_diameter = 0.008_f64.min(diameter / 2.0);
+        // the stem ends inside the sphere
+        let center = stem_length + ((diameter / 2.0).powi(2) - (stem_diameter / 2.0).powi(2)).sqrt();
+
+        let (sphere_mesh, sphere_shape) = Probe::get_sphere_shape(diameter, &Transform::translation(0.0, 0.0, center));
+        let (stem_mesh, stem_shape) = Probe::get_cylinder_shape(stem_diameter, stem_length, &Transform::from_parts(
+            Translation3::new(0.0, 0.0, stem_length / 2.0),
+            UnitQuaternion::from_axis_angle(&Vec3::x_axis(), 90.0_f64.to_radians())
+        ));
+
+        let mut object = ReferenceSphere {
+            pos: *pos,
+            sphere: window.add_trimesh(sphere_mesh, na::Vector3::from_element(1.0_f32)),
+            stem: window.add_trimesh(stem_mesh, na::Vector3::from_element(1.0_f32)),
+            sphere_shape,
+            stem_shape,
+        };
+
+        object.sphere.set_color(0.7, 0.7, 0.75);
+        object.stem.set_color(0.5, 0.5, 0.5);
+
+        Box::new(object)
+    }
+}
+
+impl CalibrationObject for ReferenceSphere {
+    fn get_probe(&self) -> Probe {
+        Probe::new(vec![
+            ("sphere", Material::Conductive(Some("sphere")), self.pos, self.sphere_shape.clone()),
+            ("stem", Material::Conductive(Some("sphere")), self.pos, self.stem_shape.clone()),
+        ])
+    }
+
+    fn render(&mut self) {
+        self.sphere.set_local_transformation(na::convert(self.pos));
+        self.stem.set_local_transformation(na::convert(self.pos));
+    }
+}
+
+impl RingGauge {
+    pub fn get_default_position() -> Vec3 {
+        Vec3::new(0.50, 0.25, 0.0)
+    }
+
+    pub fn new(window: &mut Window, pos: &Transform, inner_diameter: f64, outer_diameter: f64, height: f64) -> Box<dyn CalibrationObject> {
+        // 720 segments keep the facets of a 50mm bore within 1 micron of the circle
+        let mesh = get_ring_mesh(inner_diameter / 2.0, outer_diameter / 2.0, height, 720);
+
+        let mut object = RingGauge {
+            pos: *pos,
+            ring: window.add_trimesh(mesh.to_trimesh(), na::Vector3::from_element(1.0_f32)),
+            ring_shape: Probe::get_mesh_shape(&mesh, &Transform::identity()),
+        };
+
+        object.ring.set_color(0.7, 0.7, 0.75);
+
+        Box::new(object)
+    }
+}
+
+impl CalibrationObject for RingGauge {
+    fn get_probe(&self) -> Probe {
+        Probe::new(vec![
+            ("ring", Material::Conductive(Some("ring")), self.pos, self.ring_shape.clone()),
+        ])
+    }
+
+    fn render(&mut self) {
+        self.ring.set_local_transformation(na::convert(self.pos));
+    }
+}
+
+impl Spoilboard {
+    // The middle is `warp` higher than the edges, opposite corners are `twist` higher and lower,
+    // and every point of a 10mm grid has a random bump of up to `noise`
+    pub fn new(window: &mut Window, size: (f64, f64), warp: f64, twist: f64, noise: f64, seed: u64) -> Box<dyn CalibrationObject> {
+        let mesh = get_heightfield_mesh(size, 0.010, &mut StdRng::seed_from_u64(seed), |u, v, rng| {
+            warp * (1.0 - u * u) * (1.0 - v * v) + twist * u * v + if noise > 0.0 { rng.gen_range(-noise, noise) } else { 0.0 }
+        });
+
+        let mut object = Spoilboard {
+            board: window.add_trimesh(mesh.to_trimesh(), na::Vector3::from_element(1.0_f32)),
+            board_shape: Probe::get_mesh_shape(&mesh, &Transform::identity()),
+        };
+
+        object.board.set_color(0.25, 0.25, 0.25);
+
+        Box::new(object)
+    }
+}
+
+impl CalibrationObject for Spoilboard {
+    // covered with something conductive, like aluminium tape, to probe it
+    fn get_probe(&self) -> Probe {
+        Probe::new(vec![
+            ("spoilboard", Material::Conductive(Some("spoilboard")), Transform::identity(), self.board_shape.clone()),
+        ])
+    }
+
+    fn render(&mut self) {
+    }
+}
+
+// A grid of triangles from the origin to `size`, facing up. `height` gets the position scaled
+// from -1 to 1 along both axes.
+fn get_heightfield_mesh(size: (f64, f64), spacing: f64, rng: &mut StdRng, height: impl Fn(f64, f64, &mut StdRng) -> f64) -> MeshFile {
+    let columns = (size.0 / spacing).ceil().max(1.0) as usize;
+    let rows = (size.1 / spacing).ceil().max(1.0) as usize;
+    let mut vertices = Vec::new();
+    let mut triangles = Vec::new();
+
+    for row in 0..=rows {
+        for column in 0..=columns {
+            let (u, v) = (column as f64 / columns as f64, row as f64 / rows as f64);
+            vertices.push(Point3::new(u * size.0, v * size.1, height(2.0 * u - 1.0, 2.0 * v - 1.0, rng)));
+        }
+    }
+
+    for row in 0..rows {
+        for column in 0..columns {
+            let index = |row: usize, column: usize| row * (columns + 1) + column;
+
+            triangles.push(Point3::new(index(row, column), index(row, column + 1), index(row + 1, column + 1)));
+            triangles.push(Point3::new(index(row, column), index(row + 1, column + 1), index(row + 1, column)));
+        }
+    }
+
+    MeshFile { vertices, triangles }
+}
+
+// A tube around the Z axis from z = 0 to `height`, with the triangles facing outwards
+fn get_ring_mesh(inner_radius: f64, outer_radius: f64, height: f64, segments: usize) -> MeshFile {
+    let mut vertices = Vec::new();
+    let mut triangles = Vec::new();
+
+    for i in 0..segments {
+        let angle = i as f64 / segments as f64 * 2.0 * std::f64::consts::PI;
+        let (sin, cos) = angle.sin_cos();
+
+        vertices.push(Point3::new(outer_radius * cos, outer_radius * sin, 0.0));
+        vertices.push(Point3::new(outer_radius * cos, outer_radius * sin, height));
+        vertices.push(Point3::new(inner_radius * cos, inner_radius * sin, 0.0));
+        vertices.push(Point3::new(inner_radius * cos, inner_radius * sin, height));
+    }
+
+    for i in 0..segments {
+        let j = (i + 1) % segments;
+        let (outer_bottom, outer_top, inner_bottom, inner_top) = (|k| 4 * k, |k| 4 * k + 1, |k| 4 * k + 2, |k| 4 * k + 3);
+
+        triangles.push(Point3::new(outer_bottom(i), outer_bottom(j), outer_top(j)));
+        triangles.push(Point3::new(outer_bottom(i), outer_top(j), outer_top(i)));
+        triangles.push(Point3::new(inner_bottom(i), inner_top(j), inner_bottom(j)));
+        triangles.push(Point3::new(inner_bottom(i), inner_top(i), inner_top(j)));
+        triangles.push(Point3::new(outer_top(i), outer_top(j), inner_top(j)));
+        triangles.push(Point3::new(outer_top(i), inner_top(j), inner_top(i)));
+        triangles.push(Point3::new(outer_bottom(i), inner_bottom(j), outer_bottom(j)));
+        triangles.push(Point3::new(outer_bottom(i), inner_bottom(i), inner_bottom(j)));
+    }
+
+    MeshFile { vertices, triangles }
 }
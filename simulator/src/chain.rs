@@ -1,59 +1,213 @@
+use std::collections::HashMap;
 use std::vec::Vec;
 
-use enum_map::{Enum, EnumMap};
+use crate::parameters::{ParameterId, ParameterRegistry, Parameters};
+
 use na::geometry::UnitQuaternion;
-use na::{Isometry3, Unit, Vector3};
+use na::{DMatrix, Dynamic, Isometry3, Matrix3, Matrix6, MatrixMN, Unit, Vector3, U3, U6};
 
 pub type Transform = Isometry3<f64>;
 pub type Vec3 = Vector3<f64>;
 
-pub type Parameters<P> = EnumMap<P, f64>;
+// Rows 0-2 are the linear velocity of a frame origin, rows 3-5 its angular velocity,
+// both in world coordinates. There is one column per parameter.
+pub type Jacobian = MatrixMN<f64, U6, Dynamic>;
+
+// Covariance of the position (0-2) and orientation (3-5) of a frame, in world coordinates
+pub type PoseCovariance = Matrix6<f64>;
 
-pub trait Bounds<P> {
-    fn bounded(&self, new_value: f64) -> f64;
+pub trait Link {
+    fn get_local_transform(&self, parameters: &Parameters) -> Transform;
+
+    // The parameter driving this link and the unit twist (linear, angular) it causes,
+    // expressed in the frame at the start of the link.
+    fn get_local_twist(&self) -> Option<(ParameterId, Vec3, Vec3)> {
+        None
+    }
+
+    // The parameter the local transform depends on, if any
+    fn get_parameter(&self) -> Option<ParameterId> {
+        self.get_local_twist().map(|(param, _, _)| param)
+    }
 }
 
-pub trait Link<P: Enum<f64>> {
-    fn get_local_transform(&self, parameters: &Parameters<P>) -> Transform;
+// Frame `i` is the pose after the first `i` links, so frame 0 is the world frame
+// and frame `n` is the end of a chain with `n` links.
+pub struct Chain {
+    registry: ParameterRegistry,
+    links: Vec<Box<dyn Link>>,
+    frames: HashMap<String, usize>,
 }
 
-pub struct Chain<P> {
-    links: Vec<Box<dyn Link<P>>>,
+// Local transforms and frames of a chain for the parameters it was last updated with.
+// Updating only recomputes the links whose parameter changed, and the frames after them.
+pub struct KinematicsCache {
+    valid: bool,
+    link_values: Vec<f64>,
+    locals: Vec<Transform>,
+    frames: Vec<Transform>,
 }
 
-impl<P> Chain<P>
-where
-    P: Enum<f64> + Copy + Bounds<P>,
-    <P as Enum<f64>>::Array: Clone,
-{
-    pub fn new(links: Vec<Box<dyn Link<P>>>) -> Self {
-        Chain { links }
+pub struct IkSolution {
+    pub parameters: Parameters,
+    pub iterations: usize,
+    pub error: f64,
+    pub converged: bool,
+}
+
+impl Chain {
+    pub fn new(registry: ParameterRegistry, links: Vec<Box<dyn Link>>) -> Self {
+        let mut frames = HashMap::new();
+        frames.insert("world".to_string(), 0);
+
+        Chain { registry, links, frames }
     }
 
-    pub fn compute_all_end_poses(&self, parameters: &Parameters<P>) -> Vec<Transform> {
-        let n = self.links.len();
-        let mut t = Transform::identity();
-        let mut result = Vec::with_capacity(n);
+    pub fn name_frame(&mut self, name: &str, index: usize) {
+        assert!(index <= self.links.len());
+        self.frames.insert(name.to_string(), index);
+    }
 
-        for link in self.links.iter() {
-            t *= link.get_local_transform(parameters);
-            result.push(t);
+    pub fn get_frame(&self, name: &str) -> Option<usize> {
+        self.frames.get(name).cloned()
+    }
+
+    pub fn get_frame_name(&self, index: usize) -> Option<&str> {
+        self.frames.iter().find(|(_, i)| **i == index).map(|(name, _)| name.as_str())
+    }
+
+    pub fn get_links(&self) -> &[Box<dyn Link>] {
+        &self.links
+    }
+
+    pub fn get_registry(&self) -> &ParameterRegistry {
+        &self.registry
+    }
+
+    pub fn bounded(&self, parameter: ParameterId, new_value: f64) -> f64 {
+        self.registry.bounded(parameter, new_value)
+    }
+
+    pub fn create_cache(&self) -> KinematicsCache {
+        KinematicsCache {
+            valid: false,
+            link_values: vec![0.0; self.links.len()],
+            locals: vec![Transform::identity(); self.links.len()],
+            frames: vec![Transform::identity(); self.links.len() + 1],
+        }
+    }
+
+    pub fn update_cache<'a>(&self, cache: &'a mut KinematicsCache, parameters: &Parameters) -> &'a [Transform] {
+        let mut first_changed = if cache.valid { self.links.len() } else { 0 };
+
+        for (i, link) in self.links.iter().enumerate() {
+            let changed = match link.get_parameter() {
+                Some(param) => !cache.valid || cache.link_values[i] != parameters[param],
+                None => !cache.valid,
+            };
+
+            if changed {
+                if let Some(param) = link.get_parameter() {
+                    cache.link_values[i] = parameters[param];
+                }
+                cache.locals[i] = link.get_local_transform(parameters);
+                first_changed = first_changed.min(i);
+            }
+        }
+
+        for i in first_changed..self.links.len() {
+            cache.frames[i + 1] = cache.frames[i] * cache.locals[i];
         }
 
-        result
+        cache.valid = true;
+        &cache.frames
     }
 
-    pub fn compute_all_start_poses(&self, parameters: &Parameters<P>) -> Vec<Transform> {
-        let n = self.links.len();
+    // Jacobian of frame `index` with respect to all parameters
+    pub fn compute_jacobian(&self, parameters: &Parameters, index: usize) -> Jacobian {
         let mut t = Transform::identity();
-        let mut result = Vec::with_capacity(n);
+        let mut twists = Vec::with_capacity(index);
 
-        for link in self.links.iter() {
-            result.push(t);
+        for link in self.links.iter().take(index) {
+            if let Some((param, linear, angular)) = link.get_local_twist() {
+                let angular = t.rotation * angular;
+                let linear = t.rotation * linear + t.translation.vector.cross(&angular);
+                twists.push((param, linear, angular));
+            }
             t *= link.get_local_transform(parameters);
         }
 
-        result
+        let origin = t.translation.vector;
+        let mut jacobian = Jacobian::zeros(parameters.len());
+
+        for (param, linear, angular) in twists {
+            let velocity = linear + angular.cross(&origin);
+            let column = param.0;
+
+            for i in 0..3 {
+                jacobian[(i, column)] += velocity[i];
+                jacobian[(i + 3, column)] += angular[i];
+            }
+        }
+
+        jacobian
+    }
+
+    // First order propagation of the parameter covariance to the pose of frame `index`
+    pub fn compute_covariance(&self, parameters: &Parameters, index: usize, covariance: &DMatrix<f64>) -> PoseCovariance {
+        assert_eq!(covariance.shape(), (parameters.len(), parameters.len()));
+
+        let jacobian = self.compute_jacobian(parameters, index);
+        &jacobian * covariance * jacobian.transpose()
+    }
+
+    // Find values for the `free` parameters that move the origin of frame `index` to `target`,
+    // using damped least squares. The other parameters are kept as they are.
+    pub fn solve_position(&self, parameters: &Parameters, index: usize, target: &Vec3, free: &[ParameterId], tolerance: f64, max_iterations: usize) -> IkSolution {
+        let damping = 1e-4;
+        let mut result = parameters.clone();
+        let mut cache = self.create_cache();
+        let mut iterations = 0;
+
+        loop {
+            let error = target - self.update_cache(&mut cache, &result)[index].translation.vector;
+
+            if error.norm() <= tolerance || iterations >= max_iterations {
+                return IkSolution {
+                    parameters: result,
+                    iterations,
+                    error: error.norm(),
+                    converged: error.norm() <= tolerance,
+                };
+            }
+
+            let jacobian = self.compute_jacobian(&result, index);
+            let mut j = MatrixMN::<f64, U3, Dynamic>::zeros(free.len());
+            for (column, param) in free.iter().enumerate() {
+                for i in 0..3 {
+                    j[(i, column)] = jacobian[(i, param.0)];
+                }
+            }
+
+            let jjt = &j * j.transpose() + Matrix3::identity() * (damping * damping);
+            let step = match jjt.try_inverse() {
+                Some(inverse) => j.transpose() * (inverse * error),
+                None => {
+                    return IkSolution {
+                        parameters: result,
+                        iterations,
+                        error: error.norm(),
+                        converged: false,
+                    }
+                }
+            };
+
+            for (column, param) in free.iter().enumerate() {
+                result[*param] = self.bounded(*param, result[*param] + step[column]);
+            }
+
+            iterations += 1;
+        }
     }
 }
 
@@ -71,53 +225,249 @@ impl FixedLink {
     }
 }
 
-impl<P: Enum<f64>> Link<P> for FixedLink {
-    fn get_local_transform(&self, _parameters: &Parameters<P>) -> Transform {
+impl Link for FixedLink {
+    fn get_local_transform(&self, _parameters: &Parameters) -> Transform {
         self.transform
     }
 }
 
 // Sliding link ---------------------------------------------------------------
 
-pub struct SlidingLink<P: Enum<f64> + Copy> {
+pub struct SlidingLink {
     axis: Unit<Vec3>,
-    parameter: P,
+    parameter: ParameterId,
 }
 
-impl<P: Enum<f64> + Copy> SlidingLink<P> {
-    pub fn new(axis: &Unit<Vec3>, parameter: P) -> Self {
+impl SlidingLink {
+    pub fn new(axis: &Unit<Vec3>, parameter: ParameterId) -> Self {
         SlidingLink { axis: *axis, parameter }
     }
 }
 
-impl<P: Enum<f64> + Copy> Link<P> for SlidingLink<P> {
-    fn get_local_transform(&self, parameters: &Parameters<P>) -> Transform {
+impl Link for SlidingLink {
+    fn get_local_transform(&self, parameters: &Parameters) -> Transform {
         let param = parameters[self.parameter];
 
         Transform::translation(self.axis[0] * param, self.axis[1] * param, self.axis[2] * param)
     }
+
+    fn get_local_twist(&self) -> Option<(ParameterId, Vec3, Vec3)> {
+        Some((self.parameter, self.axis.into_inner(), Vec3::zeros()))
+    }
 }
 
 // Rotating link --------------------------------------------------------------
 
-pub struct RotatingLink<P: Enum<f64> + Copy> {
+// Rotates around an axis through `pivot`, which is given in the frame at the start of the link
+pub struct RotatingLink {
     axis: Unit<Vec3>,
+    pivot: Vec3,
     center_angle: f64,
-    parameter: P,
+    parameter: ParameterId,
 }
 
-impl<P: Enum<f64> + Copy> RotatingLink<P> {
-    pub fn new(axis: &Unit<Vec3>, center_angle: f64, parameter: P) -> Self {
+impl RotatingLink {
+    pub fn new(axis: &Unit<Vec3>, pivot: &Vec3, center_angle: f64, parameter: ParameterId) -> Self {
         RotatingLink {
             axis: *axis,
+            pivot: *pivot,
             center_angle,
             parameter,
         }
     }
 }
 
-impl<P: Enum<f64> + Copy> Link<P> for RotatingLink<P> {
-    fn get_local_transform(&self, parameters: &Parameters<P>) -> Transform {
-        Transform::identity() * UnitQuaternion::from_axis_angle(&self.axis, parameters[self.parameter] + self.center_angle)
+impl Link for RotatingLink {
+    fn get_local_transform(&self, parameters: &Parameters) -> Transform {
+        let rotation = UnitQuaternion::from_axis_angle(&self.axis, parameters[self.parameter] + self.center_angle);
+
+        Transform::from_parts((self.pivot - rotation * self.pivot).into(), rotation)
+    }
+
+    fn get_local_twist(&self) -> Option<(ParameterId, Vec3, Vec3)> {
+        Some((self.parameter, self.pivot.cross(&self.axis), self.axis.into_inner()))
+    }
+}
+
+// Screw link -----------------------------------------------------------------
+
+// Rotates around an axis through `pivot` and at the same time translates along it,
+// by `pitch` meters per radian.
+pub struct ScrewLink {
+    axis: Unit<Vec3>,
+    pivot: Vec3,
+    pitch: f64,
+    parameter: ParameterId,
+}
+
+impl ScrewLink {
+    pub fn new(axis: &Unit<Vec3>, pivot: &Vec3, pitch: f64, parameter: ParameterId) -> Self {
+        ScrewLink {
+            axis: *axis,
+            pivot: *pivot,
+            pitch,
+            parameter,
+        }
+    }
+}
+
+impl Link for ScrewLink {
+    fn get_local_transform(&self, parameters: &Parameters) -> Transform {
+        let angle = parameters[self.parameter];
+        let rotation = UnitQuaternion::from_axis_angle(&self.axis, angle);
+        let translation = self.pivot - rotation * self.pivot + self.axis.into_inner() * (self.pitch * angle);
+
+        Transform::from_parts(translation.into(), rotation)
+    }
+
+    fn get_local_twist(&self) -> Option<(ParameterId, Vec3, Vec3)> {
+        Some((self.parameter, self.pivot.cross(&self.axis) + self.axis.into_inner() * self.pitch, self.axis.into_inner()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parameters::{ParameterInfo, Unit as ParameterUnit};
+
+    // A sliding, a rotating and a screw link, with fixed links in between so none of the
+    // axes are along the world axes or through the origin
+    fn get_chain() -> (Chain, Parameters, [ParameterId; 3]) {
+        let mut registry = ParameterRegistry::new();
+        let slide = registry.add(ParameterInfo::new("slide", ParameterUnit::Length));
+        let rotate = registry.add(ParameterInfo::new("rotate", ParameterUnit::Angle));
+        let screw = registry.add(ParameterInfo::new("screw", ParameterUnit::Angle));
+
+        let links: Vec<Box<dyn Link>> = vec![
+            Box::new(FixedLink::new(&Transform::from_parts(Vec3::new(0.1, 0.2, 0.3).into(), UnitQuaternion::from_euler_angles(0.1, 0.2, 0.3)))),
+            Box::new(SlidingLink::new(&Unit::new_normalize(Vec3::new(1.0, 2.0, 3.0)), slide)),
+            Box::new(RotatingLink::new(&Vec3::z_axis(), &Vec3::new(0.05, -0.02, 0.0), 0.3, rotate)),
+            Box::new(FixedLink::new(&Transform::translation(0.2, 0.0, -0.1))),
+            Box::new(ScrewLink::new(&Unit::new_normalize(Vec3::new(0.0, 1.0, 1.0)), &Vec3::new(0.01, 0.02, 0.03), 0.01, screw)),
+            Box::new(FixedLink::new(&Transform::translation(0.0, 0.0, -0.05))),
+        ];
+
+        let chain = Chain::new(registry, links);
+        let mut parameters = chain.get_registry().get_default_parameters();
+        parameters[slide] = 0.15;
+        parameters[rotate] = 0.4;
+        parameters[screw] = 1.2;
+
+        (chain, parameters, [slide, rotate, screw])
+    }
+
+    // All frames, computed from scratch
+    fn get_frames(chain: &Chain, parameters: &Parameters) -> Vec<Transform> {
+        chain.update_cache(&mut chain.create_cache(), parameters).to_vec()
+    }
+
+    #[test]
+    fn jacobian_matches_finite_differences() {
+        let (chain, parameters, ids) = get_chain();
+        let h = 1e-6;
+
+        for index in 0..=chain.get_links().len() {
+            let jacobian = chain.compute_jacobian(&parameters, index);
+
+            for id in ids.iter() {
+                let (mut plus, mut minus) = (parameters.clone(), parameters.clone());
+                plus[*id] += h;
+                minus[*id] -= h;
+                let (plus, minus) = (get_frames(&chain, &plus)[index], get_frames(&chain, &minus)[index]);
+
+                let linear = (plus.translation.vector - minus.translation.vector) / (2.0 * h);
+                let angular = (plus.rotation * minus.rotation.inverse()).scaled_axis() / (2.0 * h);
+
+                for i in 0..3 {
+                    assert!((jacobian[(i, id.0)] - linear[i]).abs() < 1e-6, "linear {} of frame {} for {:?}", i, index, id);
+                    assert!((jacobian[(i + 3, id.0)] - angular[i]).abs() < 1e-6, "angular {} of frame {} for {:?}", i, index, id);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn solve_position_reaches_target() {
+        let (chain, parameters, ids) = get_chain();
+        let index = chain.get_links().len();
+
+        let mut moved = parameters.clone();
+        moved[ids[0]] += 0.02;
+        moved[ids[1]] -= 0.1;
+        moved[ids[2]] += 0.2;
+        let target = get_frames(&chain, &moved)[index].translation.vector;
+
+        let solution = chain.solve_position(&parameters, index, &target, &ids, 1e-9, 100);
+        let reached = get_frames(&chain, &solution.parameters)[index].translation.vector;
+
+        assert!(solution.converged);
+        assert!(solution.error <= 1e-9);
+        assert!((reached - target).norm() <= 1e-9);
+    }
+
+    #[test]
+    fn solve_position_reports_unreachable_target() {
+        let (chain, parameters, ids) = get_chain();
+        let index = chain.get_links().len();
+
+        // only sliding, so the tip can't move sideways
+        let axis = get_frames(&chain, &parameters)[1].rotation * Vec3::new(1.0, 2.0, 3.0).normalize();
+        let target = get_frames(&chain, &parameters)[index].translation.vector + axis.cross(&Vec3::x()).normalize() * 0.01;
+
+        let solution = chain.solve_position(&parameters, index, &target, &ids[..1], 1e-9, 50);
+
+        assert!(!solution.converged);
+        assert!(solution.error > 0.009);
+        assert_eq!(solution.iterations, 50);
+    }
+
+    #[test]
+    fn cache_update_matches_full_recompute() {
+        let (chain, parameters, ids) = get_chain();
+        let mut cache = chain.create_cache();
+        let before = chain.update_cache(&mut cache, &parameters).to_vec();
+
+        // the rotating link is in the middle of the chain
+        let mut changed = parameters.clone();
+        changed[ids[1]] += 0.25;
+        let updated = chain.update_cache(&mut cache, &changed).to_vec();
+        let expected = get_frames(&chain, &changed);
+
+        for (i, (updated, expected)) in updated.iter().zip(expected.iter()).enumerate() {
+            assert!((updated.to_homogeneous() - expected.to_homogeneous()).norm() < 1e-12, "frame {}", i);
+        }
+        assert_eq!(&updated[..3], &before[..3]);
+        assert!((updated[3].translation.vector - before[3].translation.vector).norm() > 1e-6);
+    }
+
+    #[test]
+    fn covariance_matches_hand_computed_case() {
+        // slide along X, then rotate around Z, with the tip 0.1 further along X
+        let mut registry = ParameterRegistry::new();
+        let slide = registry.add(ParameterInfo::new("slide", ParameterUnit::Length));
+        let rotate = registry.add(ParameterInfo::new("rotate", ParameterUnit::Angle));
+        registry.get_mut(slide).stddev = 0.001;
+        registry.get_mut(rotate).stddev = 0.01;
+
+        let links: Vec<Box<dyn Link>> = vec![
+            Box::new(SlidingLink::new(&Vec3::x_axis(), slide)),
+            Box::new(RotatingLink::new(&Vec3::z_axis(), &Vec3::zeros(), 0.0, rotate)),
+            Box::new(FixedLink::new(&Transform::translation(0.1, 0.0, 0.0))),
+        ];
+        let chain = Chain::new(registry, links);
+        let mut parameters = chain.get_registry().get_default_parameters();
+        parameters[slide] = 0.2;
+
+        let covariance = chain.compute_covariance(&parameters, 3, &chain.get_registry().get_covariance());
+
+        // the slide moves the tip along X, the rotation along Y by 0.1 per radian and turns it around Z
+        let mut expected = PoseCovariance::zeros();
+        expected[(0, 0)] = 0.001 * 0.001;
+        expected[(1, 1)] = 0.1 * 0.1 * 0.01 * 0.01;
+        expected[(5, 5)] = 0.01 * 0.01;
+        expected[(1, 5)] = 0.1 * 0.01 * 0.01;
+        expected[(5, 1)] = 0.1 * 0.01 * 0.01;
+
+        assert!((covariance - expected).norm() < 1e-15, "{}", covariance);
     }
 }
@@ -0,0 +1,127 @@
+use crate::chain::Vec3;
+use crate::mpcnc::{MPCNC, Parameter};
+use crate::parameters::{Limits, ParameterRegistry, Parameters};
+
+use std::fmt::Write;
+use std::fs;
+use std::path::PathBuf;
+
+// Grid values are in meters, the command line and the exported files use mm
+#[derive(Copy, Clone)]
+pub struct GridAxis {
+    min: f64,
+    max: f64,
+    step: f64,
+}
+
+pub struct ErrorMap {
+    file: PathBuf,
+    grid: [GridAxis; 3],
+}
+
+// Deviation of the tool tip from the commanded stepper position, like "Difference" in the GUI
+pub struct ErrorSample {
+    pub commanded: Vec3,
+    pub deviation: Vec3,
+}
+
+impl GridAxis {
+    // "MIN:MAX:STEP" in mm
+    pub fn parse(text: &str) -> Result<GridAxis, String> {
+        let values = text.split(':').map(|v| v.trim().parse::<f64>()).collect::<Result<Vec<f64>, _>>()
+            .map_err(|_| format!("invalid grid {}, expected MIN:MAX:STEP", text))?;
+
+        match values.as_slice() {
+            [min, max, step] if *step > 0.0 && min <= max => Ok(GridAxis { min: min / 1000.0, max: max / 1000.0, step: step / 1000.0 }),
+            _ => Err(format!("invalid grid {}, expected MIN:MAX:STEP with MIN <= MAX and STEP > 0", text)),
+        }
+    }
+
+    // The full travel of the parameter in `steps` steps
+    pub fn from_limits(registry: &ParameterRegistry, param: Parameter, steps: usize) -> Result<GridAxis, String> {
+        match registry.get(param.into()).limits {
+            Limits::Range(min, max) if min.is_finite() && max.is_finite() => Ok(GridAxis { min, max, step: ((max - min) / steps as f64).max(1e-6) }),
+            _ => Err(format!("{} has no limits, specify the grid for it", param.name())),
+        }
+    }
+
+    fn values(&self) -> Vec<f64> {
+        let count = ((self.max - self.min) / self.step + 1e-9).floor() as usize;
+        (0..=count).map(|i| self.min + i as f64 * self.step).collect()
+    }
+}
+
+impl ErrorMap {
+    pub fn new(file: PathBuf, grid: [GridAxis; 3]) -> ErrorMap {
+        ErrorMap { file, grid }
+    }
+
+    pub fn compute(&self, parameters: &Parameters, cnc: &MPCNC) -> Vec<ErrorSample> {
+        let mut parameters = parameters.clone();
+        let mut samples = Vec::new();
+
+        for z in self.grid[2].values() {
+            for y in self.grid[1].values() {
+                for x in self.grid[0].values() {
+                    parameters[Parameter::X] = x;
+                    parameters[Parameter::Y] = y;
+                    parameters[Parameter::Z] = z;
+
+                    let commanded = Vec3::new(x, y, z);
+                    let tip = cnc.get_end_effector_pos(&parameters).translation.vector;
+                    samples.push(ErrorSample { commanded, deviation: tip - commanded });
+                }
+            }
+        }
+
+        samples
+    }
+
+    // Writes a PLY point cloud if the file name ends in .ply, CSV otherwise
+    pub fn export(&self, parameters: &Parameters, cnc: &MPCNC) -> Result<usize, String> {
+        let samples = self.compute(parameters, cnc);
+        let is_ply = self.file.extension().and_then(|ext| ext.to_str()).map(|ext| ext.to_lowercase()) == Some("ply".to_string());
+        let contents = if is_ply { to_ply(&samples) } else { to_csv(&samples) };
+
+        fs::write(&self.file, contents).map_err(|e| format!("could not write {}: {}", self.file.display(), e))?;
+        Ok(samples.len())
+    }
+
+    pub fn get_file(&self) -> &PathBuf {
+        &self.file
+    }
+}
+
+fn to_csv(samples: &[ErrorSample]) -> String {
+    let mut csv = String::from("x,y,z,dx,dy,dz,error\n");
+
+    for sample in samples {
+        let (p, d) = (sample.commanded * 1000.0, sample.deviation * 1000.0);
+        writeln!(csv, "{:.3},{:.3},{:.3},{:.6},{:.6},{:.6},{:.6}", p.x, p.y, p.z, d.x, d.y, d.z, d.norm()).unwrap();
+    }
+
+    csv
+}
+
+// Points at the commanded positions, colored from green (smallest error) to red (largest error)
+fn to_ply(samples: &[ErrorSample]) -> String {
+    let min_error = samples.iter().map(|s| s.deviation.norm()).fold(f64::INFINITY, f64::min);
+    let max_error = samples.iter().map(|s| s.deviation.norm()).fold(0.0, f64::max);
+    let mut ply = String::new();
+
+    writeln!(ply, "ply\nformat ascii 1.0\nelement vertex {}", samples.len()).unwrap();
+    for property in &["float x", "float y", "float z", "float dx", "float dy", "float dz", "float error", "uchar red", "uchar green", "uchar blue"] {
+        writeln!(ply, "property {}", property).unwrap();
+    }
+    writeln!(ply, "end_header").unwrap();
+
+    for sample in samples {
+        let (p, d) = (sample.commanded * 1000.0, sample.deviation * 1000.0);
+        let t = if max_error > min_error { (sample.deviation.norm() - min_error) / (max_error - min_error) } else { 0.0 };
+
+        writeln!(ply, "{:.3} {:.3} {:.3} {:.6} {:.6} {:.6} {:.6} {} {} 0",
+            p.x, p.y, p.z, d.x, d.y, d.z, d.norm(), (t * 255.0).round() as u8, ((1.0 - t) * 255.0).round() as u8).unwrap();
+    }
+
+    ply
+}
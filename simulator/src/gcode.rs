@@ -1,19 +1,64 @@
-use crate::chain::{Vec3, Parameters, Bounds};
+use crate::chain::{Transform, Vec3};
 use crate::mpcnc::{MPCNC, Parameter};
+use crate::parameters::{ParameterId, Parameters};
 use crate::calibration_object::CalibrationObject;
+use crate::error_map::ErrorMap;
+use crate::misalignment::Misalignment;
+use crate::probe::{Contact, Probe, ProbeInput};
+use crate::stylus::Stylus;
+use crate::thermal::ThermalDrift;
+
+use enum_map::EnumMap;
+use std::fs;
+use std::path::PathBuf;
+use std::slice;
 
 type Field = Option<Option<f64>>;
 
+// G38.8/G38.9 move the arm to the target in workspace coordinates, where the probe input is
+// triggered, and rotate it away and back until it touches again
+struct ArmRotation {
+    target: Vec3,
+    clockwise: bool,
+    input: ProbeInput,
+}
+
 pub struct GCode {
     origin: Vec3,
+    feedrate: f64,
+    rapid_feedrate: f64,
+    elapsed_time: f64,
+    heating_time: f64,
+    spindle_on: bool,
+    drift: ThermalDrift,
+    applied_drift: EnumMap<Parameter, f64>,
+    urdf_file: Option<PathBuf>,
+    error_map: Option<ErrorMap>,
+    probe_tolerance: f64,
+    stylus: Stylus,
+    misalignment: Option<Misalignment>,
 }
 
 impl GCode {
-    pub fn new() -> GCode {
-        GCode { origin: Vec3::new(0.0, 0.0, 0.0) }
+    pub fn new(drift: ThermalDrift, urdf_file: Option<PathBuf>, error_map: Option<ErrorMap>, probe_tolerance: f64, stylus: Stylus, misalignment: Option<Misalignment>) -> GCode {
+        GCode {
+            origin: Vec3::new(0.0, 0.0, 0.0),
+            feedrate: 0.008,
+            rapid_feedrate: 0.050,
+            elapsed_time: 0.0,
+            heating_time: 0.0,
+            spindle_on: false,
+            drift,
+            applied_drift: EnumMap::new(),
+            urdf_file,
+            error_map,
+            probe_tolerance,
+            stylus,
+            misalignment,
+        }
     }
 
-    pub fn parse(&mut self, line: String, parameters: &mut Parameters<Parameter>, cnc: &MPCNC, calibration_object: &Box<dyn CalibrationObject>) {
+    pub fn parse(&mut self, line: String, parameters: &mut Parameters, cnc: &MPCNC, calibration_object: &Box<dyn CalibrationObject>) {
         let line = line.split(";").collect::<Vec<&str>>()[0];
         let line = line.split("(").collect::<Vec<&str>>()[0];
         let line = line.split("#").collect::<Vec<&str>>()[0];
@@ -22,8 +67,11 @@ impl GCode {
         let fields = line.split(" ").collect::<Vec<&str>>();
         let a = self.parse_field(&fields, "A");
         let b = self.parse_field(&fields, "B");
+        let f = self.parse_field(&fields, "F");
         let o = self.parse_field(&fields, "O");
+        let p = self.parse_field(&fields, "P");
         let r = self.parse_field(&fields, "R");
+        let s = self.parse_field(&fields, "S");
 
         let x = self.parse_field(&fields, "X");
         let y = self.parse_field(&fields, "Y");
@@ -32,25 +80,55 @@ impl GCode {
         let has_x = x.is_some();
         let has_y = y.is_some();
         let has_z = z.is_some();
+        let tip = (x, y, z);
 
         let x = if let Some(Some(x)) = x { x / 1000.0 } else { parameters[Parameter::X] - self.origin.x };
         let y = if let Some(Some(y)) = y { y / 1000.0 } else { parameters[Parameter::Y] - self.origin.y };
         let z = if let Some(Some(z)) = z { z / 1000.0 } else { parameters[Parameter::Z] - self.origin.z };
 
+        if let Some(Some(f)) = f {
+            if f > 0.0 {
+                self.feedrate = f / 60.0 / 1000.0;
+            }
+        }
+
+        // the drift of the previous moves is applied before this one, so the machine doesn't move
+        // while the probe is in contact at the end of a probing move
+        self.apply_drift(parameters);
+
+        let start = self.get_machine_position(parameters);
+        let feedrate = if fields[0] == "G0" { self.rapid_feedrate } else { self.feedrate };
+
         match fields[0] {
             "G0" | "G1" => self.go_to(x, y, z, parameters),
+            "G4" => self.dwell(p, s),
 
             "G28" => self.home(has_x, has_y, has_z, parameters, cnc, calibration_object),
-            "G38.2" => self.probe_towards(x, y, z, parameters, cnc, calibration_object),
-            "G38.8" => self.rotate_arm(x, y, z, true, parameters, cnc, calibration_object),
-            "G38.9" => self.rotate_arm(x, y, z, false, parameters, cnc, calibration_object),
+            "G38.2" => if let Some(input) = self.select_input(p, calibration_object) {
+                self.probe_towards(Vec3::new(x, y, z), &input, parameters, cnc, calibration_object)
+            },
+            "G38.8" => if let Some(input) = self.select_input(p, calibration_object) {
+                self.rotate_arm(&ArmRotation { target: Vec3::new(x, y, z), clockwise: true, input }, parameters, cnc, calibration_object)
+            },
+            "G38.9" => if let Some(input) = self.select_input(p, calibration_object) {
+                self.rotate_arm(&ArmRotation { target: Vec3::new(x, y, z), clockwise: false, input }, parameters, cnc, calibration_object)
+            },
             "G92" => self.set_position(x, y, z, parameters),
             "M114" => self.get_position(parameters),
             "M119" => self.endstops(parameters, cnc, calibration_object),
+            "M3" | "M4" => self.set_spindle_on(true),
+            "M5" => self.set_spindle_on(false),
+            "M31" => self.print_time(),
+            "M503" => self.report_parameters(parameters, cnc),
 
             "M800" => self.set_z_axis(a, b, parameters),
             "M801" => self.set_spindle(a, b, r, parameters),
             "M802" => self.set_endmill(a, b, o, parameters),
+            "M803" => self.move_tip_to(tip, r.is_some(), parameters, cnc),
+            "M804" => self.report_uncertainty(s, parameters, cnc),
+            "M805" => self.save_urdf(parameters, cnc),
+            "M806" => self.save_error_map(parameters, cnc),
+            "M807" => self.report_contacts(parameters, cnc, calibration_object),
 
             "G90" => self.ok(),
             "M17" => self.ok(),
@@ -61,9 +139,40 @@ impl GCode {
             "" => {},
             _ => println!("error:unknown gcode command: {}", line),
         }
+
+        let distance = (self.get_machine_position(parameters) - start).norm();
+        self.advance_time(distance / feedrate);
+    }
+
+    pub fn get_elapsed_time(&self) -> f64 {
+        self.elapsed_time
+    }
+
+    pub fn get_applied_drift(&self) -> &EnumMap<Parameter, f64> {
+        &self.applied_drift
+    }
+
+    pub fn get_misalignment(&self) -> Option<&Misalignment> {
+        self.misalignment.as_ref()
     }
 
-    pub fn get_workspace_position(&self, parameters: &Parameters<Parameter>) -> Vec3 {
+    // Does nothing if no URDF file was given
+    pub fn export_urdf(&self, parameters: &Parameters, cnc: &MPCNC) -> Result<(), String> {
+        match &self.urdf_file {
+            Some(file) => fs::write(file, cnc.to_urdf(parameters)).map_err(|e| format!("could not write {}: {}", file.display(), e)),
+            None => Ok(()),
+        }
+    }
+
+    // Does nothing if no error map file was given
+    pub fn export_error_map(&self, parameters: &Parameters, cnc: &MPCNC) -> Result<(), String> {
+        match &self.error_map {
+            Some(error_map) => error_map.export(parameters, cnc).map(|_| ()),
+            None => Ok(()),
+        }
+    }
+
+    pub fn get_workspace_position(&self, parameters: &Parameters) -> Vec3 {
         Vec3::new(
             parameters[Parameter::X] - self.origin.x,
             parameters[Parameter::Y] - self.origin.y,
@@ -71,14 +180,63 @@ impl GCode {
         )
     }
 
-    fn go_to(&self, x: f64, y: f64, z: f64, parameters: &mut Parameters<Parameter>) {
+    fn get_machine_position(&self, parameters: &Parameters) -> Vec3 {
+        Vec3::new(parameters[Parameter::X], parameters[Parameter::Y], parameters[Parameter::Z])
+    }
+
+    fn advance_time(&mut self, seconds: f64) {
+        self.elapsed_time += seconds;
+
+        if self.spindle_on || !self.drift.is_spindle_driven() {
+            self.heating_time += seconds;
+        }
+    }
+
+    fn apply_drift(&mut self, parameters: &mut Parameters) {
+        // only apply the change in drift, so values set with M800-M802 are kept
+        let offsets = self.drift.get_offsets(self.heating_time);
+        for (param, value) in offsets.iter() {
+            parameters[param] += value - self.applied_drift[param];
+        }
+        self.applied_drift = offsets;
+    }
+
+    fn dwell(&mut self, milliseconds: Field, seconds: Field) {
+        let mut duration = 0.0;
+        if let Some(Some(p)) = milliseconds { duration += p / 1000.0; }
+        if let Some(Some(s)) = seconds { duration += s; }
+
+        self.advance_time(duration);
+        self.ok();
+    }
+
+    fn set_spindle_on(&mut self, on: bool) {
+        self.spindle_on = on;
+        self.ok();
+    }
+
+    fn print_time(&self) {
+        let seconds = self.elapsed_time.round() as u64;
+        println!("echo:Print time: {}h {}m {}s", seconds / 3600, (seconds / 60) % 60, seconds % 60);
+
+        if self.drift.is_enabled() {
+            println!("echo:Thermal drift: growth {:.4}mm, spindle A{:.4} B{:.4} (heating {:.0}s)",
+                -self.applied_drift[Parameter::SpindleGrowth] * 1000.0,
+                self.applied_drift[Parameter::SpindleX].to_degrees(),
+                self.applied_drift[Parameter::SpindleY].to_degrees(),
+                self.heating_time);
+        }
+        self.ok();
+    }
+
+    fn go_to(&self, x: f64, y: f64, z: f64, parameters: &mut Parameters) {
         parameters[Parameter::X] = x + self.origin.x;
         parameters[Parameter::Y] = y + self.origin.y;
         parameters[Parameter::Z] = z + self.origin.z;
         self.ok();
     }
 
-    fn set_position(&mut self, x: f64, y: f64, z: f64, parameters: &mut Parameters<Parameter>) {
+    fn set_position(&mut self, x: f64, y: f64, z: f64, parameters: &mut Parameters) {
         self.origin = Vec3::new(
             parameters[Parameter::X] - x,
             parameters[Parameter::Y] - y,
@@ -87,105 +245,282 @@ impl GCode {
         self.ok();
     }
 
-    fn set_z_axis(&mut self, a: Field, b: Field, parameters: &mut Parameters<Parameter>) {
-        if let Some(Some(a)) = a { parameters[Parameter::ZAxisX] = a.to_radians(); }
-        if let Some(Some(b)) = b { parameters[Parameter::ZAxisY] = b.to_radians(); }
+    fn is_parameter_hidden(&self, id: ParameterId) -> bool {
+        match &self.misalignment {
+            Some(misalignment) => misalignment.is_hidden(id),
+            None => false,
+        }
+    }
+
+    // The misalignment drawn with --seed can't be changed, or the blind test would be spoiled
+    fn is_hidden(&self, changes: &[(Field, Parameter)]) -> bool {
+        let hidden = changes.iter().find(|(field, param)| field.is_some() && self.is_parameter_hidden(ParameterId::from(*param)));
+
+        if let Some((_, param)) = hidden {
+            println!("error:{} is hidden until exit, it was drawn with --seed", param.name());
+        }
+        hidden.is_some()
+    }
+
+    // The machine keeps drifting after a parameter is set, so the drift so far is added to it
+    fn set_parameter(&self, param: Parameter, value: f64, parameters: &mut Parameters) {
+        parameters[param] = value + self.applied_drift[param];
+    }
+
+    fn set_z_axis(&mut self, a: Field, b: Field, parameters: &mut Parameters) {
+        if self.is_hidden(&[(a, Parameter::ZAxisX), (b, Parameter::ZAxisY)]) {
+            return;
+        }
+        if let Some(Some(a)) = a { self.set_parameter(Parameter::ZAxisX, a.to_radians(), parameters); }
+        if let Some(Some(b)) = b { self.set_parameter(Parameter::ZAxisY, b.to_radians(), parameters); }
+        self.ok();
+    }
+
+    fn set_spindle(&mut self, a: Field, b: Field, r: Field, parameters: &mut Parameters) {
+        if self.is_hidden(&[(a, Parameter::SpindleX), (b, Parameter::SpindleY)]) {
+            return;
+        }
+        if let Some(Some(a)) = a { self.set_parameter(Parameter::SpindleX, a.to_radians(), parameters); }
+        if let Some(Some(b)) = b { self.set_parameter(Parameter::SpindleY, b.to_radians(), parameters); }
+        if let Some(Some(r)) = r { self.set_parameter(Parameter::Spindle, r.to_radians(), parameters); }
+        self.ok();
+    }
+
+    fn set_endmill(&mut self, a: Field, b: Field, o: Field, parameters: &mut Parameters) {
+        if self.is_hidden(&[(a, Parameter::EndmillX), (b, Parameter::EndmillY), (o, Parameter::EndmillOffset)]) {
+            return;
+        }
+        if let Some(Some(a)) = a { self.set_parameter(Parameter::EndmillX, a.to_radians(), parameters); }
+        if let Some(Some(b)) = b { self.set_parameter(Parameter::EndmillY, b.to_radians(), parameters); }
+        if let Some(Some(o)) = o { self.set_parameter(Parameter::EndmillOffset, o / 1000.0, parameters); }
         self.ok();
     }
 
-    fn set_spindle(&mut self, a: Field, b: Field, r: Field, parameters: &mut Parameters<Parameter>) {
-        if let Some(Some(a)) = a { parameters[Parameter::SpindleX] = a.to_radians(); }
-        if let Some(Some(b)) = b { parameters[Parameter::SpindleY] = b.to_radians(); }
-        if let Some(Some(r)) = r { parameters[Parameter::Spindle] = r.to_radians(); }
+    // The target is in world coordinates, like the end mill position in the GUI
+    fn move_tip_to(&self, (x, y, z): (Field, Field, Field), rotate_spindle: bool, parameters: &mut Parameters, cnc: &MPCNC) {
+        // the stepper positions after placing the tip would reveal the hidden misalignment
+        if self.misalignment.is_some() {
+            println!("error:M803 would reveal the hidden misalignment, it was drawn with --seed");
+            return;
+        }
+
+        let mut target = cnc.get_end_effector_pos(parameters).translation.vector;
+        if let Some(Some(x)) = x { target.x = x / 1000.0; }
+        if let Some(Some(y)) = y { target.y = y / 1000.0; }
+        if let Some(Some(z)) = z { target.z = z / 1000.0; }
+
+        let solution = cnc.solve_end_effector_pos(parameters, &target, rotate_spindle);
+
+        if solution.converged {
+            *parameters = solution.parameters;
+            println!("echo:Tip placed after {} iterations, error {:.6}mm", solution.iterations, solution.error * 1000.0);
+            self.ok();
+        } else {
+            println!("error:could not place tip, error {:.6}mm after {} iterations", solution.error * 1000.0, solution.iterations);
+        }
+    }
+
+    fn report_parameters(&self, parameters: &Parameters, cnc: &MPCNC) {
+        for (id, info) in cnc.get_chain().get_registry().iter() {
+            if self.is_parameter_hidden(id) {
+                println!("echo:{} = hidden", info.name);
+            } else if !info.hidden {
+                println!("echo:{} = {:.4} {}", info.name, info.unit.to_display(parameters[id]), info.unit.get_display_name());
+            }
+        }
         self.ok();
     }
 
-    fn set_endmill(&mut self, a: Field, b: Field, o: Field, parameters: &mut Parameters<Parameter>) {
-        if let Some(Some(a)) = a { parameters[Parameter::EndmillX] = a.to_radians(); }
-        if let Some(Some(b)) = b { parameters[Parameter::EndmillY] = b.to_radians(); }
-        if let Some(Some(o)) = o { parameters[Parameter::EndmillOffset] = o / 1000.0; }
+    // S is the number of standard deviations to report, 1 by default
+    fn report_uncertainty(&self, sigmas: Field, parameters: &Parameters, cnc: &MPCNC) {
+        let sigmas = if let Some(Some(s)) = sigmas { s } else { 1.0 };
+        let covariance = cnc.get_end_effector_covariance(parameters);
+        let pos = self.get_workspace_position(parameters);
+
+        println!("echo:Tool tip uncertainty ({} sigma) at X{:.3} Y{:.3} Z{:.3}: X+/-{:.4} Y+/-{:.4} Z+/-{:.4} mm",
+            sigmas, pos.x * 1000.0, pos.y * 1000.0, pos.z * 1000.0,
+            sigmas * covariance[(0, 0)].sqrt() * 1000.0,
+            sigmas * covariance[(1, 1)].sqrt() * 1000.0,
+            sigmas * covariance[(2, 2)].sqrt() * 1000.0);
         self.ok();
     }
 
-    fn get_position(&self, parameters: &mut Parameters<Parameter>) {
+    fn save_urdf(&self, parameters: &Parameters, cnc: &MPCNC) {
+        if self.urdf_file.is_none() {
+            println!("error:no URDF file given, use --urdf");
+        } else if self.misalignment.is_some() {
+            println!("error:the URDF would reveal the hidden misalignment, it is written on exit");
+        } else if let Err(e) = self.export_urdf(parameters, cnc) {
+            println!("error:{}", e);
+        } else {
+            self.ok();
+        }
+    }
+
+    fn save_error_map(&self, parameters: &Parameters, cnc: &MPCNC) {
+        match &self.error_map {
+            None => println!("error:no error map file given, use --error-map"),
+            Some(_) if self.misalignment.is_some() => println!("error:the error map would reveal the hidden misalignment, it is written on exit"),
+            Some(error_map) => match error_map.export(parameters, cnc) {
+                Ok(count) => {
+                    println!("echo:Wrote {} points to {}", count, error_map.get_file().display());
+                    self.ok();
+                }
+                Err(e) => println!("error:{}", e),
+            },
+        }
+    }
+
+    fn get_position(&self, parameters: &mut Parameters) {
         let pos = self.get_workspace_position(parameters);
         println!("X:{:.3} Y:{:.3} Z:{:.3}", pos.x * 1000.0, pos.y * 1000.0, pos.z * 1000.0);
         self.ok();
     }
 
-    fn endstops(&self, parameters: &mut Parameters<Parameter>, cnc: &MPCNC, calibration_object: &Box<dyn CalibrationObject>) {
-        let triggered = cnc.get_probe(parameters).is_touching(&calibration_object.get_probe());
-        println!("z_min: {}", if triggered { "TRIGGERED" } else { "open" });
+    fn endstops(&self, parameters: &mut Parameters, cnc: &MPCNC, calibration_object: &Box<dyn CalibrationObject>) {
+        let contacts = cnc.get_probe(parameters).get_contacts(&calibration_object.get_probe().join(cnc.get_fixed_probe()));
+
+        for input in calibration_object.get_inputs() {
+            println!("{}: {}", input.pin, if input.is_triggered(&contacts) { "TRIGGERED" } else { "open" });
+        }
         self.ok();
     }
 
-    fn home(&mut self, x: bool, y: bool, z: bool, parameters: &mut Parameters<Parameter>, cnc: &MPCNC, calibration_object: &Box<dyn CalibrationObject>) {
+    // P selects a probe input by its position in M119, the first one by default
+    fn select_input(&self, p: Field, calibration_object: &Box<dyn CalibrationObject>) -> Option<ProbeInput> {
+        let mut inputs = calibration_object.get_inputs();
+        let index = match p {
+            Some(Some(p)) if p >= 0.0 && p.fract() == 0.0 => p as usize,
+            Some(Some(_)) => inputs.len(),
+            _ => 0,
+        };
+
+        if index < inputs.len() {
+            Some(inputs.swap_remove(index))
+        } else {
+            println!("error:unknown probe input, there are {} inputs", inputs.len());
+            None
+        }
+    }
+
+    fn report_contacts(&self, parameters: &Parameters, cnc: &MPCNC, calibration_object: &Box<dyn CalibrationObject>) {
+        // the contact points would reveal the hidden pose of the calibration objects
+        if self.misalignment.is_some() {
+            println!("error:M807 would reveal the hidden object pose, it was drawn with --seed");
+            return;
+        }
+
+        let (probe, object) = (cnc.get_probe(parameters), calibration_object.get_probe().join(cnc.get_fixed_probe()));
+        let contacts = probe.get_contacts(&object);
+
+        if contacts.is_empty() {
+            match probe.distance_to(&object) {
+                Some(separation) => println!("echo:No contacts, gap {:.6}mm between {} and {}",
+                    separation.distance * 1000.0, separation.probe_shape, separation.object_shape),
+                None => println!("echo:No contacts"),
+            }
+        }
+        for contact in contacts.iter() {
+            self.print_contact(contact);
+        }
+        self.ok();
+    }
+
+    fn print_contact(&self, contact: &Contact) {
+        let circuit = match contact.nets {
+            Some((a, b)) if a == b => format!("triggers {}", a),
+            Some((a, b)) => format!("connects {} to {}", a, b),
+            None => "collision".to_string(),
+        };
+        if self.misalignment.is_some() {
+            println!("echo:Contact {} - {} ({})", contact.probe_shape, contact.object_shape, circuit);
+            return;
+        }
+        println!("echo:Contact {} - {} ({}) at X{:.4} Y{:.4} Z{:.4}, normal {:.3} {:.3} {:.3}, depth {:.6}mm",
+            contact.probe_shape, contact.object_shape, circuit,
+            contact.point.x * 1000.0, contact.point.y * 1000.0, contact.point.z * 1000.0,
+            contact.normal.x, contact.normal.y, contact.normal.z, contact.depth * 1000.0);
+    }
+
+    fn home(&mut self, x: bool, y: bool, z: bool, parameters: &mut Parameters, cnc: &MPCNC, calibration_object: &Box<dyn CalibrationObject>) {
         let pos = self.get_workspace_position(parameters);
 
         if x || y || !z { 
             println!("error:only G28 Z is supported");
         } else {
-            self.probe_towards(pos.x, pos.y, -self.origin.z - 0.050, parameters, cnc, calibration_object);
+            let input = calibration_object.get_inputs().swap_remove(0);
+            self.probe_towards(Vec3::new(pos.x, pos.y, -self.origin.z - 0.050), &input, parameters, cnc, calibration_object);
             self.origin.z = parameters[Parameter::Z];
         }
     }
 
-    fn probe_towards(&self, x: f64, y: f64, z: f64, parameters: &mut Parameters<Parameter>, cnc: &MPCNC, calibration_object: &Box<dyn CalibrationObject>) {
-        let movement = Vec3::new(x, y, z) - self.get_workspace_position(parameters);
-        let mut toi = cnc.get_probe(parameters).approx_time_of_impact(&calibration_object.get_probe(), &movement);
+    fn probe_towards(&self, target: Vec3, input: &ProbeInput, parameters: &mut Parameters, cnc: &MPCNC, calibration_object: &Box<dyn CalibrationObject>) {
+        let movement = target - self.get_workspace_position(parameters);
+        let start = self.get_machine_position(parameters);
 
-        let microns = movement.norm() * 1e6;
-        let time_per_micron = 1.0 / microns;
+        // X, Y and Z are sliding links, so the tool moves along a straight line without rotating
+        let mut end = parameters.clone();
+        end[Parameter::X] = start.x + movement.x;
+        end[Parameter::Y] = start.y + movement.y;
+        end[Parameter::Z] = start.z + movement.z;
+        let tool_movement = cnc.get_end_effector_pos(&end).translation.vector - cnc.get_end_effector_pos(parameters).translation.vector;
 
-        let start_x = parameters[Parameter::X];
-        let start_y = parameters[Parameter::Y];
-        let start_z = parameters[Parameter::Z];
+        let object = calibration_object.get_probe().join(cnc.get_fixed_probe());
+        let tip = cnc.get_end_effector_pos(parameters);
+        let toi = self.get_probe_travel(&cnc.get_probe(parameters), &object, &tool_movement, &tip, input);
 
-        // back off until the probe is not touching anymore
-        loop {
-            let delta = movement * toi;
-            parameters[Parameter::X] = start_x + delta.x;
-            parameters[Parameter::Y] = start_y + delta.y;
-            parameters[Parameter::Z] = start_z + delta.z;
+        let delta = movement * toi;
+        parameters[Parameter::X] = start.x + delta.x;
+        parameters[Parameter::Y] = start.y + delta.y;
+        parameters[Parameter::Z] = start.z + delta.z;
 
-            if !cnc.get_probe(parameters).is_touching(&calibration_object.get_probe()) || toi == 0.0 {
-                break;
-            }
-
-            toi = (toi - time_per_micron).max(0.0);
-        }
+        self.ok();
+    }
 
-        // move until the probe is touching again
-        loop {
-            let delta = movement * toi;
-            parameters[Parameter::X] = start_x + delta.x;
-            parameters[Parameter::Y] = start_y + delta.y;
-            parameters[Parameter::Z] = start_z + delta.z;
-            
-            if cnc.get_probe(parameters).is_touching(&calibration_object.get_probe()) || toi == 1.0 {
-                break;
+    // How far the tool moves along `movement` until it stops, as a fraction of it. The stylus only
+    // bends when the move closes the probe circuit, probing again while it is triggered stays put.
+    fn get_probe_travel(&self, probe: &Probe, object: &Probe, movement: &Vec3, tip: &Transform, input: &ProbeInput) -> f64 {
+        match probe.probe_towards(object, movement, self.probe_tolerance) {
+            Some((toi, contact)) => {
+                self.print_contact(&contact);
+                // an insulating part stops the machine without triggering the probe, and so does
+                // a part that is wired to another input
+                if contact.nets.is_none() {
+                    println!("error:Collision between {} and {}", contact.probe_shape, contact.object_shape);
+                    toi
+                } else if !input.is_triggered(slice::from_ref(&contact)) {
+                    println!("error:Probe input {} was not triggered", input.pin);
+                    toi
+                } else if toi > 0.0 || !input.is_triggered(&probe.get_contacts(object)) {
+                    self.print_deflection(&contact, tip);
+                    (toi + self.stylus.get_overtravel(&contact, tip, movement)).min(1.0)
+                } else {
+                    toi
+                }
             }
-
-            toi = (toi + time_per_micron).min(1.0);
+            None => 1.0,
         }
-
-        self.ok();
     }
 
-    fn rotate_arm(&self, x: f64, y: f64, z: f64, clockwise: bool, parameters: &mut Parameters<Parameter>, cnc: &MPCNC, calibration_object: &Box<dyn CalibrationObject>) {
-        parameters[Parameter::X] = x + self.origin.x;
-        parameters[Parameter::Y] = y + self.origin.y;
-        parameters[Parameter::Z] = z + self.origin.z;
+    fn rotate_arm(&self, rotation: &ArmRotation, parameters: &mut Parameters, cnc: &MPCNC, calibration_object: &Box<dyn CalibrationObject>) {
+        let (input, clockwise) = (&rotation.input, rotation.clockwise);
+        parameters[Parameter::X] = rotation.target.x + self.origin.x;
+        parameters[Parameter::Y] = rotation.target.y + self.origin.y;
+        parameters[Parameter::Z] = rotation.target.z + self.origin.z;
         
-        assert!(cnc.get_probe(parameters).is_touching(&calibration_object.get_probe()));
+        let object = calibration_object.get_probe().join(cnc.get_fixed_probe());
+        if !input.is_triggered(&cnc.get_probe(parameters).get_contacts(&object)) {
+            println!("error:Probe input {} is not triggered at the start of the rotation", input.pin);
+            return;
+        }
 
         // back off until the probe is not touching anymore
         let delta = 0.0001_f64.atan2(0.150) * if clockwise { 1.0 } else { -1.0 };
         let start_angle = parameters[Parameter::Spindle];
         for i in 0..100 {
-            parameters[Parameter::Spindle] = Parameter::Spindle.bounded(start_angle - (i as f64) * delta);
+            parameters[Parameter::Spindle] = cnc.bounded(Parameter::Spindle, start_angle - (i as f64) * delta);
 
-            if !cnc.get_probe(parameters).is_touching(&calibration_object.get_probe()) {
+            if !input.is_triggered(&cnc.get_probe(parameters).get_contacts(&object)) {
                 break;
             }
         }
@@ -194,15 +529,35 @@ impl GCode {
         let delta = 0.000001_f64.atan2(0.150) * if clockwise { 1.0 } else { -1.0 };
         let start_angle = parameters[Parameter::Spindle];
         for i in 0..100 {
-            parameters[Parameter::Spindle] = Parameter::Spindle.bounded(start_angle + (i as f64) * delta);
+            parameters[Parameter::Spindle] = cnc.bounded(Parameter::Spindle, start_angle + (i as f64) * delta);
             
-            if cnc.get_probe(parameters).is_touching(&calibration_object.get_probe()) {
+            let contacts = cnc.get_probe(parameters).get_contacts(&object);
+            if let Some(contact) = contacts.iter().find(|contact| input.is_triggered(slice::from_ref(*contact))) {
+                // keep rotating while the stylus bends, the tip moves sideways as the arm turns
+                let tip = cnc.get_end_effector_pos(parameters);
+                let mut turned = parameters.clone();
+                turned[Parameter::Spindle] += delta;
+                let movement = (cnc.get_end_effector_pos(&turned).translation.vector - tip.translation.vector) / delta.abs();
+
+                self.print_deflection(contact, &tip);
+                // a grazing contact would bend the stylus forever, stop after a few degrees
+                let overtravel = self.stylus.get_overtravel(contact, &tip, &movement).min(0.1);
+                parameters[Parameter::Spindle] = cnc.bounded(Parameter::Spindle, parameters[Parameter::Spindle] + overtravel * delta.signum());
+
                 self.ok();
                 return;
             }
         }
 
-        assert!(false);
+        println!("error:Probe input {} was not triggered after rotating back", input.pin);
+    }
+
+    fn print_deflection(&self, contact: &Contact, tip: &Transform) {
+        if self.stylus.is_enabled() {
+            let deflection = self.stylus.get_deflection(contact, tip);
+            println!("echo:Stylus deflected {:.4}mm before triggering, X{:.4} Y{:.4} Z{:.4}",
+                deflection.norm() * 1000.0, deflection.x * 1000.0, deflection.y * 1000.0, deflection.z * 1000.0);
+        }
     }
 
     fn ok(&self) {
@@ -225,3 +580,39 @@ impl GCode {
         None
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::probe::{Material, PROBE_NET};
+
+    const TOLERANCE: f64 = 1e-6;
+
+    // A 3 mm ball, like the tip of a ball end mill
+    fn get_ball(center: &Vec3) -> Probe {
+        let (_, ball) = Probe::get_sphere_shape(0.003, &Transform::identity());
+        Probe::new(vec![("ball", Material::Conductive(Some(PROBE_NET)), Transform::translation(center.x, center.y, center.z), ball)])
+    }
+
+    // A 2 mm wall, 10 mm to the side of the ball
+    fn get_wall() -> Probe {
+        let (_, wall) = Probe::get_box_shape(0.002, 0.02, 0.02, &Transform::identity());
+        Probe::new(vec![("wall", Material::Conductive(Some("object")), Transform::translation(0.010, 0.0, 0.0), wall)])
+    }
+
+    #[test]
+    fn probing_again_does_not_bend_the_stylus_further() {
+        // 1 mm of deflection before the probe triggers
+        let gcode = GCode::new(ThermalDrift::new(0.0, 0.0, 0.0, 1.0, false), None, None, TOLERANCE, Stylus::new(100.0, 0.1), None);
+        let input = ProbeInput::new("z_min", vec!["object"]);
+        let (wall, target) = (get_wall(), Vec3::new(0.020, 0.0, 0.0));
+
+        let first = gcode.get_probe_travel(&get_ball(&Vec3::zeros()), &wall, &target, &Transform::identity(), &input);
+        let expected = (0.009 - 0.0015 + 0.001) / target.x;
+        assert!((first - expected).abs() <= 2.0 * TOLERANCE / target.x, "moved {} instead of {}", first, expected);
+
+        let position = target * first;
+        let second = gcode.get_probe_travel(&get_ball(&position), &wall, &(target - position), &Transform::identity(), &input);
+        assert_eq!(second, 0.0);
+    }
+}
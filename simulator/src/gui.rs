@@ -1,5 +1,7 @@
-use crate::chain::{Parameters, Transform, Bounds};
-use crate::mpcnc::{Parameter};
+use crate::chain::Transform;
+use crate::mpcnc::{MPCNC, Parameter};
+use crate::parameters::Parameters;
+use crate::probe::{Contact, Separation};
 use kiss3d::event::{Action, Key, WindowEvent, Modifiers};
 use kiss3d::window::Window;
 use na::{Point3};
@@ -15,17 +17,36 @@ pub fn draw_transform(window: &mut Window, transform: &Transform, size: f64) {
     window.draw_line(&origin, &z, &Point3::new(0.0, 0.0, 1.0));
 }
 
-pub fn update_parameter(mods: Modifiers, parameters: &mut Parameters<Parameter>, param: Parameter, delta: f64) {
+// A small cross at the contact point, with a line along the normal
+pub fn draw_contact(window: &mut Window, contact: &Contact) {
+    let size = 0.002;
+    // magenta for contacts that trigger the probe, orange for collisions
+    let color = if contact.nets.is_some() { Point3::new(1.0, 0.0, 1.0) } else { Point3::new(1.0, 0.5, 0.0) };
+    let point = Point3::from(contact.point);
+
+    for axis in 0..3 {
+        let mut offset = na::Vector3::zeros();
+        offset[axis] = size / 2.0;
+        window.draw_line(&na::convert(point - offset), &na::convert(point + offset), &color);
+    }
+    window.draw_line(&na::convert(point), &na::convert(point - contact.normal * size * 2.0), &color);
+}
+
+pub fn draw_separation(window: &mut Window, separation: &Separation) {
+    window.draw_line(&na::convert(Point3::from(separation.probe_point)), &na::convert(Point3::from(separation.object_point)), &Point3::new(0.0, 1.0, 1.0));
+}
+
+pub fn update_parameter(mods: Modifiers, parameters: &mut Parameters, cnc: &MPCNC, param: Parameter, delta: f64) {
     let mut d = delta;
 
     if mods.contains(Modifiers::Shift) { d = d / 10.0; }
     if mods.contains(Modifiers::Control) { d = d / 10.0; }
     if mods.contains(Modifiers::Alt) { d = d / 10.0; }
 
-    parameters[param] = param.bounded(parameters[param] + d);
+    parameters[param] = cnc.bounded(param, parameters[param] + d);
 }
 
-pub fn handle_events(window: &mut Window, parameters: &mut Parameters<Parameter>, keyboard_control: bool) {
+pub fn handle_events(window: &mut Window, parameters: &mut Parameters, cnc: &MPCNC, keyboard_control: bool) {
     let dpos = 0.005;
     let dangle = 1.0_f64.to_radians();
 
@@ -33,32 +54,32 @@ pub fn handle_events(window: &mut Window, parameters: &mut Parameters<Parameter>
         if keyboard_control {
             match event.value {
                 WindowEvent::Key(key, Action::Press, mods) => match key {
-                    Key::Up => update_parameter(mods, parameters, Parameter::Y, dpos),
-                    Key::Down => update_parameter(mods, parameters, Parameter::Y, -dpos),
-                    Key::Left => update_parameter(mods, parameters, Parameter::X, -dpos),
-                    Key::Right => update_parameter(mods, parameters, Parameter::X, dpos),
-                    Key::A => update_parameter(mods, parameters, Parameter::Z, dpos),
-                    Key::Z => update_parameter(mods, parameters, Parameter::Z, -dpos),
-                    Key::S => update_parameter(mods, parameters, Parameter::Spindle, dangle),
-                    Key::X => update_parameter(mods, parameters, Parameter::Spindle, -dangle),
+                    Key::Up => update_parameter(mods, parameters, cnc, Parameter::Y, dpos),
+                    Key::Down => update_parameter(mods, parameters, cnc, Parameter::Y, -dpos),
+                    Key::Left => update_parameter(mods, parameters, cnc, Parameter::X, -dpos),
+                    Key::Right => update_parameter(mods, parameters, cnc, Parameter::X, dpos),
+                    Key::A => update_parameter(mods, parameters, cnc, Parameter::Z, dpos),
+                    Key::Z => update_parameter(mods, parameters, cnc, Parameter::Z, -dpos),
+                    Key::S => update_parameter(mods, parameters, cnc, Parameter::Spindle, dangle),
+                    Key::X => update_parameter(mods, parameters, cnc, Parameter::Spindle, -dangle),
 
-                    Key::D => update_parameter(mods, parameters, Parameter::ZAxisX, dangle),
-                    Key::C => update_parameter(mods, parameters, Parameter::ZAxisX, -dangle),
-                    Key::F => update_parameter(mods, parameters, Parameter::ZAxisY, dangle),
-                    Key::V => update_parameter(mods, parameters, Parameter::ZAxisY, -dangle),
+                    Key::D => update_parameter(mods, parameters, cnc, Parameter::ZAxisX, dangle),
+                    Key::C => update_parameter(mods, parameters, cnc, Parameter::ZAxisX, -dangle),
+                    Key::F => update_parameter(mods, parameters, cnc, Parameter::ZAxisY, dangle),
+                    Key::V => update_parameter(mods, parameters, cnc, Parameter::ZAxisY, -dangle),
 
-                    Key::G => update_parameter(mods, parameters, Parameter::SpindleX, dangle),
-                    Key::B => update_parameter(mods, parameters, Parameter::SpindleX, -dangle),
-                    Key::H => update_parameter(mods, parameters, Parameter::SpindleY, dangle),
-                    Key::N => update_parameter(mods, parameters, Parameter::SpindleY, -dangle),
+                    Key::G => update_parameter(mods, parameters, cnc, Parameter::SpindleX, dangle),
+                    Key::B => update_parameter(mods, parameters, cnc, Parameter::SpindleX, -dangle),
+                    Key::H => update_parameter(mods, parameters, cnc, Parameter::SpindleY, dangle),
+                    Key::N => update_parameter(mods, parameters, cnc, Parameter::SpindleY, -dangle),
 
-                    Key::J => update_parameter(mods, parameters, Parameter::EndmillX, dangle),
-                    Key::M => update_parameter(mods, parameters, Parameter::EndmillX, -dangle),
-                    Key::K => update_parameter(mods, parameters, Parameter::EndmillY, dangle),
-                    Key::Comma => update_parameter(mods, parameters, Parameter::EndmillY, -dangle),
+                    Key::J => update_parameter(mods, parameters, cnc, Parameter::EndmillX, dangle),
+                    Key::M => update_parameter(mods, parameters, cnc, Parameter::EndmillX, -dangle),
+                    Key::K => update_parameter(mods, parameters, cnc, Parameter::EndmillY, dangle),
+                    Key::Comma => update_parameter(mods, parameters, cnc, Parameter::EndmillY, -dangle),
 
-                    Key::L => update_parameter(mods, parameters, Parameter::EndmillOffset, dpos / 10.0),
-                    Key::Period => update_parameter(mods, parameters, Parameter::EndmillOffset, -dpos / 10.0),
+                    Key::L => update_parameter(mods, parameters, cnc, Parameter::EndmillOffset, dpos / 10.0),
+                    Key::Period => update_parameter(mods, parameters, cnc, Parameter::EndmillOffset, -dpos / 10.0),
 
                     _ => {}
                 },
@@ -0,0 +1,283 @@
+use crate::chain::{Chain, FixedLink, Link, RotatingLink, ScrewLink, SlidingLink, Transform, Vec3};
+use crate::mpcnc::Parameter;
+use crate::parameters::{Limits, ParameterId, ParameterInfo, ParameterRegistry, Unit as ParameterUnit};
+
+use na::{Translation3, Unit, UnitQuaternion};
+use serde::Deserialize;
+use std::fs;
+use std::path::Path;
+
+// Machine description files use meters for lengths and degrees for angles.
+
+#[derive(Deserialize)]
+pub struct MachineDefinition {
+    pub name: String,
+    pub parameters: Vec<ParameterDefinition>,
+    pub links: Vec<NamedLinkDefinition>,
+    pub meshes: Vec<MeshDefinition>,
+    pub spoilboard: SpoilboardDefinition,
+}
+
+// Parameters that are not built in (see mpcnc::Parameter) are added to the registry
+#[derive(Deserialize)]
+pub struct ParameterDefinition {
+    pub name: String,
+    #[serde(default)]
+    pub unit: UnitDefinition,
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+    #[serde(default)]
+    pub default: f64,
+    #[serde(default)]
+    pub hidden: bool,
+    #[serde(default)]
+    pub stddev: f64,
+}
+
+#[derive(Deserialize, Copy, Clone, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum UnitDefinition {
+    #[default]
+    Length,
+    Angle,
+    Scalar,
+}
+
+// `frame` names the frame at the end of the link
+#[derive(Deserialize)]
+pub struct NamedLinkDefinition {
+    pub frame: Option<String>,
+    #[serde(flatten)]
+    pub link: LinkDefinition,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum LinkDefinition {
+    Fixed {
+        translation: [f64; 3],
+        #[serde(default)]
+        rotation: [f64; 3],
+    },
+    Sliding {
+        axis: [f64; 3],
+        parameter: String,
+    },
+    Rotating {
+        axis: [f64; 3],
+        parameter: String,
+        #[serde(default)]
+        pivot: [f64; 3],
+        #[serde(default)]
+        center_angle: f64,
+    },
+    // `lead` is the distance moved along the axis per revolution
+    Screw {
+        axis: [f64; 3],
+        parameter: String,
+        #[serde(default)]
+        pivot: [f64; 3],
+        lead: f64,
+    },
+}
+
+// The top of the spoilboard is at z = 0, from the origin to `size` along X and Y
+#[derive(Deserialize)]
+pub struct SpoilboardDefinition {
+    pub size: [f64; 2],
+}
+
+#[derive(Deserialize)]
+pub struct MeshDefinition {
+    pub file: String,
+    pub frame: String,
+    pub color: [f32; 3],
+    #[serde(default = "default_scale")]
+    pub scale: f32,
+    // world axes ("x", "y" or "z") along which the mesh does not follow its frame
+    #[serde(default)]
+    pub stationary_axes: Vec<String>,
+    // the triangles of the file are used for collisions with the calibration object as well
+    #[serde(default)]
+    pub collision: bool,
+    // conductive collision meshes can be wired to a net of the probe circuit
+    #[serde(default = "default_material")]
+    pub material: MaterialDefinition,
+    pub net: Option<String>,
+}
+
+#[derive(Deserialize, Copy, Clone)]
+#[serde(rename_all = "lowercase")]
+pub enum MaterialDefinition {
+    Insulating,
+    Conductive,
+}
+
+fn default_scale() -> f32 {
+    1.0
+}
+
+fn default_material() -> MaterialDefinition {
+    MaterialDefinition::Insulating
+}
+
+impl MachineDefinition {
+    pub fn load(path: &Path) -> Result<MachineDefinition, String> {
+        let contents = fs::read_to_string(path).map_err(|e| format!("could not read {}: {}", path.display(), e))?;
+        let machine: MachineDefinition = serde_json::from_str(&contents).map_err(|e| format!("could not parse {}: {}", path.display(), e))?;
+
+        machine.validate()?;
+        Ok(machine)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        for (i, definition) in self.parameters.iter().enumerate() {
+            if self.parameters[..i].iter().any(|other| other.name == definition.name) {
+                return Err(format!("parameter {} is defined more than once", definition.name));
+            }
+        }
+
+        let registry = self.build_registry();
+        let mut frames = vec!["world"];
+        let mut driven = Vec::new();
+
+        for named_link in self.links.iter() {
+            if let Some(frame) = &named_link.frame {
+                if frames.contains(&frame.as_str()) {
+                    return Err(format!("frame {} is defined more than once", frame));
+                }
+                frames.push(frame);
+            }
+
+            let (kind, parameter, axis) = match &named_link.link {
+                LinkDefinition::Fixed { .. } => continue,
+                LinkDefinition::Sliding { parameter, axis } => ("sliding", parameter, axis),
+                LinkDefinition::Rotating { parameter, axis, .. } => ("rotating", parameter, axis),
+                LinkDefinition::Screw { parameter, axis, .. } => ("screw", parameter, axis),
+            };
+            driven.push((kind, find_parameter(&registry, parameter)?));
+            parse_axis(axis)?;
+        }
+
+        // G-code moves the tool in a straight line with the steppers and turns the arm with the spindle
+        for (param, kind) in [(Parameter::X, "sliding"), (Parameter::Y, "sliding"), (Parameter::Z, "sliding"), (Parameter::Spindle, "rotating")].iter() {
+            let kinds = driven.iter().filter(|(_, id)| *id == ParameterId::from(*param)).map(|(kind, _)| *kind).collect::<Vec<&str>>();
+
+            if kinds.is_empty() || kinds.iter().any(|other| other != kind) {
+                return Err(format!("parameter {} must drive {} links only", param.name(), kind));
+            }
+        }
+
+        for mesh in self.meshes.iter() {
+            if !frames.contains(&mesh.frame.as_str()) {
+                return Err(format!("mesh {} refers to unknown frame {}", mesh.file, mesh.frame));
+            }
+        }
+
+        if self.spoilboard.size.iter().any(|size| *size <= 0.0) {
+            return Err(format!("invalid spoilboard size {:?}", self.spoilboard.size));
+        }
+
+        if !frames.contains(&"tool_tip") {
+            return Err("there is no tool_tip frame to mount the tool to".to_string());
+        }
+
+        Ok(())
+    }
+
+    pub fn build_registry(&self) -> ParameterRegistry {
+        let mut registry = Parameter::create_registry();
+
+        for definition in self.parameters.iter() {
+            let unit = match definition.unit {
+                UnitDefinition::Length => ParameterUnit::Length,
+                UnitDefinition::Angle => ParameterUnit::Angle,
+                UnitDefinition::Scalar => ParameterUnit::Scalar,
+            };
+            let id = match registry.find(&definition.name) {
+                Some(id) => id,
+                None => registry.add(ParameterInfo::new(&definition.name, unit)),
+            };
+
+            let info = registry.get_mut(id);
+            info.unit = unit;
+            info.limits = definition.get_limits();
+            info.default = definition.get_default();
+            info.hidden = definition.hidden;
+            info.stddev = definition.stddev * definition.get_scale();
+        }
+
+        registry
+    }
+
+    pub fn build_chain(&self) -> Chain {
+        let registry = self.build_registry();
+        let links = self.links.iter().map(|named_link| -> Box<dyn Link> {
+            match &named_link.link {
+                LinkDefinition::Fixed { translation, rotation } => Box::new(FixedLink::new(&Transform::from_parts(
+                    Translation3::new(translation[0], translation[1], translation[2]),
+                    UnitQuaternion::from_euler_angles(rotation[0].to_radians(), rotation[1].to_radians(), rotation[2].to_radians()),
+                ))),
+                LinkDefinition::Sliding { axis, parameter } => {
+                    Box::new(SlidingLink::new(&parse_axis(axis).unwrap(), find_parameter(&registry, parameter).unwrap()))
+                }
+                LinkDefinition::Rotating { axis, parameter, pivot, center_angle } => Box::new(RotatingLink::new(
+                    &parse_axis(axis).unwrap(),
+                    &Vec3::from(*pivot),
+                    center_angle.to_radians(),
+                    find_parameter(&registry, parameter).unwrap(),
+                )),
+                LinkDefinition::Screw { axis, parameter, pivot, lead } => Box::new(ScrewLink::new(
+                    &parse_axis(axis).unwrap(),
+                    &Vec3::from(*pivot),
+                    lead / (2.0 * std::f64::consts::PI),
+                    find_parameter(&registry, parameter).unwrap(),
+                )),
+            }
+        });
+
+        let links = links.collect();
+        let mut chain = Chain::new(registry, links);
+
+        for (i, named_link) in self.links.iter().enumerate() {
+            if let Some(frame) = &named_link.frame {
+                chain.name_frame(frame, i + 1);
+            }
+        }
+
+        chain
+    }
+}
+
+impl ParameterDefinition {
+    fn get_scale(&self) -> f64 {
+        match self.unit {
+            UnitDefinition::Angle => std::f64::consts::PI / 180.0,
+            _ => 1.0,
+        }
+    }
+
+    fn get_default(&self) -> f64 {
+        self.default * self.get_scale()
+    }
+
+    fn get_limits(&self) -> Limits {
+        let scale = self.get_scale();
+
+        match (self.min, self.max) {
+            (None, None) => match self.unit {
+                UnitDefinition::Angle => Limits::Angle,
+                _ => Limits::Unbounded,
+            },
+            (min, max) => Limits::Range(min.map_or(f64::NEG_INFINITY, |v| v * scale), max.map_or(f64::INFINITY, |v| v * scale)),
+        }
+    }
+}
+
+fn find_parameter(registry: &ParameterRegistry, name: &str) -> Result<ParameterId, String> {
+    registry.find(name).ok_or_else(|| format!("unknown parameter {}", name))
+}
+
+fn parse_axis(axis: &[f64; 3]) -> Result<Unit<Vec3>, String> {
+    Unit::try_new(Vec3::new(axis[0], axis[1], axis[2]), 1e-9).ok_or_else(|| format!("invalid axis {:?}", axis))
+}
@@ -6,18 +6,34 @@ mod mpcnc;
 mod calibration_object;
 mod probe;
 mod gcode;
+mod machine;
+mod parameters;
+mod thermal;
+mod stylus;
+mod urdf;
+mod error_map;
+mod mesh_file;
+mod scene;
+mod misalignment;
 
-use crate::chain::{Parameters};
-use crate::mpcnc::{MPCNC, Parameter};
-use crate::calibration_object::CalibrationObject;
+use crate::chain::Vec3;
+use crate::mpcnc::{MPCNC, Parameter, Tool};
+use crate::parameters::Parameters;
+use crate::calibration_object::{CalibrationObject, FeelerGauge, GaugeBlock, ObjectDefinition, ReferenceSphere, RingGauge, TwoWires};
 use crate::gcode::GCode;
+use crate::error_map::{ErrorMap, GridAxis};
+use crate::machine::MachineDefinition;
+use crate::misalignment::Misalignment;
+use crate::scene::{Scene, SceneDefinition};
+use crate::stylus::Stylus;
+use crate::thermal::ThermalDrift;
 
 use kiss3d::camera::ArcBall;
 use kiss3d::light::Light;
 use kiss3d::text::Font;
 use kiss3d::window::Window;
 use na::{Point2, Point3};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::time::Instant;
 use clap::{App, Arg};
 use std::io;
@@ -37,44 +53,344 @@ fn main() {
             .long("fast")
             .short("f")
             .help("process gcode as fast as possible without updating the GUI between lines"))
+        .arg(Arg::with_name("machine")
+            .long("machine")
+            .short("m")
+            .value_name("FILE")
+            .takes_value(true)
+            .default_value("machines/mpcnc.json")
+            .help("machine description file with the kinematic chain and meshes"))
+        .arg(Arg::with_name("tool")
+            .long("tool")
+            .value_name("TOOL")
+            .takes_value(true)
+            .possible_values(&["endmill", "ball-end", "v-bit", "touch-probe"])
+            .default_value("endmill")
+            .help("tool in the collet"))
+        .arg(Arg::with_name("object")
+            .long("object")
+            .value_name("OBJECT")
+            .takes_value(true)
+            .possible_values(&["feeler-gauge", "two-wires", "gauge-block", "reference-sphere", "ring-gauge"])
+            .default_value("feeler-gauge")
+            .help("calibration object on the spoilboard"))
+        .arg(Arg::with_name("scene")
+            .long("scene")
+            .value_name("FILE")
+            .takes_value(true)
+            .help("scene file with any number of calibration objects, instead of --object and its options"))
+        .arg(Arg::with_name("object-pos")
+            .long("object-pos")
+            .value_name("X:Y:Z")
+            .takes_value(true)
+            .help("position of the calibration object in mm, defaults to where the script expects it"))
+        .arg(Arg::with_name("object-rotation")
+            .long("object-rotation")
+            .value_name("DEGREES")
+            .takes_value(true)
+            .help("rotation of the calibration object around Z"))
+        .arg(Arg::with_name("object-tilt-x")
+            .long("object-tilt-x")
+            .value_name("DEGREES")
+            .takes_value(true)
+            .help("tilt of the calibration object around X, like on an uneven spoilboard"))
+        .arg(Arg::with_name("object-tilt-y")
+            .long("object-tilt-y")
+            .value_name("DEGREES")
+            .takes_value(true)
+            .help("tilt of the calibration object around Y, like on an uneven spoilboard"))
+        .arg(Arg::with_name("gauge-size")
+            .long("gauge-size")
+            .value_name("LENGTH:WIDTH:THICKNESS")
+            .takes_value(true)
+            .default_value("89:13:0.8")
+            .help("size of the feeler gauge in mm"))
+        .arg(Arg::with_name("gauge-rotation")
+            .long("gauge-rotation")
+            .value_name("DEGREES")
+            .takes_value(true)
+            .help("rotation of the feeler gauge in its holder around Z"))
+        .arg(Arg::with_name("gauge-tilt-x")
+            .long("gauge-tilt-x")
+            .value_name("DEGREES")
+            .takes_value(true)
+            .help("tilt of the feeler gauge in its holder around X"))
+        .arg(Arg::with_name("gauge-tilt-y")
+            .long("gauge-tilt-y")
+            .value_name("DEGREES")
+            .takes_value(true)
+            .help("tilt of the feeler gauge in its holder around Y"))
+        .arg(Arg::with_name("block-size")
+            .long("block-size")
+            .value_name("X:Y:Z")
+            .takes_value(true)
+            .default_value("76.2:50.8:25.4")
+            .help("size of the gauge block in mm, a 1-2-3 block by default"))
+        .arg(Arg::with_name("sphere-diameter")
+            .long("sphere-diameter")
+            .value_name("MM")
+            .takes_value(true)
+            .default_value("25")
+            .help("diameter of the reference sphere"))
+        .arg(Arg::with_name("stem-length")
+            .long("stem-length")
+            .value_name("MM")
+            .takes_value(true)
+            .default_value("10")
+            .help("length of the stem below the reference sphere, short enough to probe its equator"))
+        .arg(Arg::with_name("ring-size")
+            .long("ring-size")
+            .value_name("INNER:OUTER:HEIGHT")
+            .takes_value(true)
+            .default_value("50:80:20")
+            .help("inner and outer diameter and height of the ring gauge in mm"))
+        .arg(Arg::with_name("spoilboard-warp")
+            .long("spoilboard-warp")
+            .value_name("MM")
+            .takes_value(true)
+            .help("how much higher the middle of the spoilboard is than its edges"))
+        .arg(Arg::with_name("spoilboard-twist")
+            .long("spoilboard-twist")
+            .value_name("MM")
+            .takes_value(true)
+            .help("how much higher and lower the opposite corners of the spoilboard are"))
+        .arg(Arg::with_name("spoilboard-noise")
+            .long("spoilboard-noise")
+            .value_name("MM")
+            .takes_value(true)
+            .help("random bumps in the spoilboard surface of up to this height"))
+        .arg(Arg::with_name("spoilboard-seed")
+            .long("spoilboard-seed")
+            .value_name("SEED")
+            .takes_value(true)
+            .default_value("0")
+            .help("seed for the random bumps in the spoilboard surface"))
+        .arg(Arg::with_name("wire-diameter")
+            .long("wire-diameter")
+            .value_name("MM")
+            .takes_value(true)
+            .default_value("0.1")
+            .help("diameter of the wires of the two wires object"))
+        .arg(Arg::with_name("drift-z")
+            .long("drift-z")
+            .value_name("MM")
+            .takes_value(true)
+            .help("thermal growth of the spindle towards the tip once fully warmed up"))
+        .arg(Arg::with_name("drift-tilt-x")
+            .long("drift-tilt-x")
+            .value_name("DEGREES")
+            .takes_value(true)
+            .help("thermal tilt of the spindle around X once fully warmed up"))
+        .arg(Arg::with_name("drift-tilt-y")
+            .long("drift-tilt-y")
+            .value_name("DEGREES")
+            .takes_value(true)
+            .help("thermal tilt of the spindle around Y once fully warmed up"))
+        .arg(Arg::with_name("drift-time-constant")
+            .long("drift-time-constant")
+            .value_name("MINUTES")
+            .takes_value(true)
+            .default_value("10")
+            .help("time for the thermal drift to reach 63% of its final value"))
+        .arg(Arg::with_name("drift-spindle-only")
+            .long("drift-spindle-only")
+            .help("only heat up while the spindle is on (M3/M4) instead of during the whole session"))
+        .arg(Arg::with_name("seed")
+            .long("seed")
+            .value_name("SEED")
+            .takes_value(true)
+            .help("draw the misalignment and the pose of the calibration objects at random, hidden until exit"))
+        .arg(Arg::with_name("misalignment")
+            .long("misalignment")
+            .value_name("NAME=DISTRIBUTION")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .requires("seed")
+            .help("distribution of a misalignment parameter or object offset in mm or degrees, like EndmillOffset=normal:150:1 or ObjectRotation=uniform:-5:5"))
+        .arg(Arg::with_name("probe-tolerance")
+            .long("probe-tolerance")
+            .value_name("MICRONS")
+            .takes_value(true)
+            .default_value("0.1")
+            .help("accuracy of the contact position found by probing moves"))
+        .arg(Arg::with_name("stylus-stiffness")
+            .long("stylus-stiffness")
+            .value_name("N/MM")
+            .takes_value(true)
+            .help("sideways stiffness at the tip of the tool, which is rigid by default"))
+        .arg(Arg::with_name("trigger-force")
+            .long("trigger-force")
+            .value_name("N")
+            .takes_value(true)
+            .help("contact force needed to trigger the probe, the tool bends until it is reached"))
+        .arg(Arg::with_name("urdf")
+            .long("urdf")
+            .value_name("FILE")
+            .takes_value(true)
+            .help("export the kinematic chain as URDF to this file at startup and on M805"))
+        .arg(Arg::with_name("error-map")
+            .long("error-map")
+            .value_name("FILE")
+            .takes_value(true)
+            .help("write the tool tip deviation over a grid to this CSV or PLY file at startup and on M806"))
+        .arg(Arg::with_name("grid-x")
+            .long("grid-x")
+            .value_name("MIN:MAX:STEP")
+            .takes_value(true)
+            .help("X positions of the error map in mm, defaults to the X travel in 10 steps"))
+        .arg(Arg::with_name("grid-y")
+            .long("grid-y")
+            .value_name("MIN:MAX:STEP")
+            .takes_value(true)
+            .help("Y positions of the error map in mm, defaults to the Y travel in 10 steps"))
+        .arg(Arg::with_name("grid-z")
+            .long("grid-z")
+            .value_name("MIN:MAX:STEP")
+            .takes_value(true)
+            .help("Z positions of the error map in mm, defaults to the Z travel in 10 steps"))
         .get_matches();
 
-    simulator(!matches.is_present("no-keyboard"), matches.is_present("fast"));
+    let value = |name| matches.value_of(name).map_or(0.0, |v: &str| v.parse::<f64>().expect("expected floating point value"));
+    let drift = ThermalDrift::new(
+        value("drift-z") / 1000.0,
+        value("drift-tilt-x").to_radians(),
+        value("drift-tilt-y").to_radians(),
+        value("drift-time-constant") * 60.0,
+        matches.is_present("drift-spindle-only"),
+    );
+
+    let machine_file = Path::new(matches.value_of("machine").unwrap());
+    let machine = MachineDefinition::load(machine_file).unwrap_or_else(|e| panic!("Invalid machine description: {}", e));
+
+    let probe_tolerance = value("probe-tolerance") / 1e6;
+    if probe_tolerance <= 0.0 {
+        panic!("Invalid --probe-tolerance: expected a positive value");
+    }
+    let stylus = Stylus::new(matches.value_of("stylus-stiffness").map_or(f64::INFINITY, |_| value("stylus-stiffness") * 1000.0), value("trigger-force"));
+    let urdf_file = matches.value_of("urdf").map(PathBuf::from);
+
+    let registry = machine.build_registry();
+    let grid_axis = |name, param| match matches.value_of(name) {
+        Some(text) => GridAxis::parse(text),
+        None => GridAxis::from_limits(&registry, param, 10),
+    }.unwrap_or_else(|e| panic!("Invalid error map grid: {}", e));
+    let error_map = matches.value_of("error-map").map(|file| {
+        ErrorMap::new(PathBuf::from(file), [grid_axis("grid-x", Parameter::X), grid_axis("grid-y", Parameter::Y), grid_axis("grid-z", Parameter::Z)])
+    });
+
+    let tool = Tool::from_name(matches.value_of("tool").unwrap()).unwrap();
+
+    let vector = |name| matches.value_of(name).map(|text| parse_vector(text).unwrap_or_else(|e| panic!("Invalid --{}: {}", name, e)));
+    let pose = |default: fn() -> Vec3| calibration_object::get_pose(&vector("object-pos").unwrap_or_else(default),
+        value("object-rotation").to_radians(), value("object-tilt-x").to_radians(), value("object-tilt-y").to_radians());
+    let object = |name| match name {
+        "two-wires" => ObjectDefinition::TwoWires {
+            pos: pose(TwoWires::get_default_position),
+            wire_diameter: value("wire-diameter") / 1000.0,
+        },
+        "gauge-block" => ObjectDefinition::GaugeBlock {
+            pos: pose(GaugeBlock::get_default_position),
+            size: vector("block-size").unwrap(),
+        },
+        "reference-sphere" => ObjectDefinition::ReferenceSphere {
+            pos: pose(ReferenceSphere::get_default_position),
+            diameter: value("sphere-diameter") / 1000.0,
+            stem_length: value("stem-length") / 1000.0,
+        },
+        "ring-gauge" => {
+            let size = vector("ring-size").unwrap();
+            ObjectDefinition::RingGauge { pos: pose(RingGauge::get_default_position), inner_diameter: size.x, outer_diameter: size.y, height: size.z }
+        },
+        _ => ObjectDefinition::FeelerGauge {
+            pos: pose(FeelerGauge::get_default_position),
+            size: vector("gauge-size").unwrap(),
+            tilt: calibration_object::get_pose(&Vec3::zeros(), value("gauge-rotation").to_radians(), value("gauge-tilt-x").to_radians(), value("gauge-tilt-y").to_radians()).rotation,
+        },
+    };
+    let mut objects = match matches.value_of("scene") {
+        Some(file) => SceneDefinition::load(Path::new(file)).unwrap_or_else(|e| panic!("Invalid scene: {}", e)).get_objects(),
+        None => vec![(None, object(matches.value_of("object").unwrap()))],
+    };
+    for (_, object) in objects.iter() {
+        object.validate().unwrap_or_else(|e| panic!("Invalid calibration object: {}", e));
+    }
+    objects.push((None, ObjectDefinition::Spoilboard {
+        size: (machine.spoilboard.size[0], machine.spoilboard.size[1]),
+        warp: value("spoilboard-warp") / 1000.0,
+        twist: value("spoilboard-twist") / 1000.0,
+        noise: value("spoilboard-noise") / 1000.0,
+        seed: matches.value_of("spoilboard-seed").unwrap().parse().expect("expected an integer seed"),
+    }));
+
+    let distributions = matches.values_of("misalignment").map_or(Vec::new(), |values| values.map(|text| {
+        misalignment::parse_distribution(text).unwrap_or_else(|e| panic!("Invalid --misalignment: {}", e))
+    }).collect());
+    let misalignment = matches.value_of("seed").map(|seed| {
+        Misalignment::draw(seed.parse().expect("expected an integer seed"), &registry, &distributions, &mut objects)
+            .unwrap_or_else(|e| panic!("Invalid --misalignment: {}", e))
+    });
+
+    // the keyboard controls would change the hidden misalignment
+    let manual_control = !matches.is_present("no-keyboard") && misalignment.is_none();
+    let gcode = gcode::GCode::new(drift, urdf_file, error_map, probe_tolerance, stylus, misalignment);
+
+    simulator(manual_control, matches.is_present("fast"), &machine, tool, &objects, gcode);
 }
 
-fn simulator(manual_control: bool, fast: bool) {
+fn simulator(manual_control: bool, fast: bool, machine: &MachineDefinition, tool: Tool, objects: &[(Option<String>, ObjectDefinition)], mut gcode: GCode) {
     let resources_dir = Path::new("resources");
     let font = Font::default();
     let mut now = Instant::now();
     let stdin_channel = spawn_stdin_channel();
-    let mut gcode = gcode::GCode::new();
 
-    let mut window = Window::new_with_size("Simulator", 1280, 720);
+    let mut window = Window::new_with_size(&format!("Simulator - {}", machine.name), 1280, 720);
     let eye = na::Point3::new(0.5, -1.0, 1.0);
     let at = na::Point3::new(0.5, 0.5, 0.0);
     let mut camera = ArcBall::new(eye, at);
 
-    let mut cnc = mpcnc::MPCNC::new(&mut window, &resources_dir);
-    //let mut calibration_object = calibration_object::TwoWires::new(&mut window, &resources_dir);
-    let mut calibration_object = calibration_object::FeelerGauge::new(&mut window, &resources_dir);
+    let mut cnc = mpcnc::MPCNC::new(&mut window, &resources_dir, machine, tool);
+    let mut calibration_object = Scene::new(&mut window, &resources_dir, objects);
     let mut parameters = cnc.get_default_parameters();
 
+    // the exports would reveal a hidden misalignment, so they are written on exit instead
+    match gcode.get_misalignment() {
+        Some(misalignment) => misalignment.apply(&mut parameters),
+        None => export(&gcode, &parameters, &cnc),
+    }
+
+    // probing from inside an object would never trigger, and it can't happen on a real machine
+    if let Some(contact) = cnc.get_probe(&parameters).get_contacts(&calibration_object.get_probe()).first() {
+        panic!("Invalid start position: {} touches {}, move the calibration object away from the tool", contact.probe_shape, contact.object_shape);
+    }
+
     window.set_light(Light::StickToCamera);
 
     while window.render_with_camera(&mut camera) {
-        gui::handle_events(&mut window, &mut parameters, manual_control);
+        gui::handle_events(&mut window, &mut parameters, &cnc, manual_control);
         handle_gcode(&stdin_channel, &mut gcode, &mut parameters, &cnc, &calibration_object, fast);
 
         let endmill_tip = cnc.get_end_effector_pos(&parameters);
+        // the tip frame, the contact points and the gap would reveal the hidden misalignment
+        let hidden = gcode.get_misalignment().is_some();
         
         gui::draw_transform(&mut window, &chain::Transform::identity(), 1.0);
-        gui::draw_transform(&mut window, &endmill_tip, 0.1);
+        if !hidden {
+            gui::draw_transform(&mut window, &endmill_tip, 0.1);
+        }
         cnc.render(&mut window, &parameters, false);
         calibration_object.render();
 
         let cnc_probe = &cnc.get_probe(&parameters);
-        let cal_probe = &calibration_object.get_probe();
-        let triggered = cnc_probe.is_touching(cal_probe);
+        let cal_probe = &calibration_object.get_probe().join(cnc.get_fixed_probe());
+        let triggered = cnc_probe.is_triggered(cal_probe);
+        let contacts = cnc_probe.get_contacts(cal_probe);
+
+        if !hidden {
+            for contact in contacts.iter() {
+                gui::draw_contact(&mut window, contact);
+            }
+        }
 
         window.draw_text(&format!("Workspace: X = {:7.3}mm, Y = {:7.3}mm, Z = {:7.3}mm", 
                 gcode.get_workspace_position(&parameters).x * 1000.0, 
@@ -87,26 +403,77 @@ fn simulator(manual_control: bool, fast: bool) {
                 parameters[Parameter::Spindle].to_degrees()),
             &Point2::new(0.0, 30.0), 30.0, &font, &Point3::new(1.0, 1.0, 1.0));
 
-        window.draw_text(&format!("End mill: X = {:7.3}mm, Y = {:7.3}mm, Z = {:7.3}mm", 
-                endmill_tip.translation.x * 1000.0, endmill_tip.translation.y * 1000.0, endmill_tip.translation.z * 1000.0),
-            &Point2::new(0.0, 60.0), 30.0, &font, &Point3::new(1.0, 1.0, 1.0));
+        if hidden {
+            window.draw_text("End mill: hidden until exit", &Point2::new(0.0, 60.0), 30.0, &font, &Point3::new(1.0, 1.0, 1.0));
+        } else {
+            window.draw_text(&format!("End mill: X = {:7.3}mm, Y = {:7.3}mm, Z = {:7.3}mm", 
+                    endmill_tip.translation.x * 1000.0, endmill_tip.translation.y * 1000.0, endmill_tip.translation.z * 1000.0),
+                &Point2::new(0.0, 60.0), 30.0, &font, &Point3::new(1.0, 1.0, 1.0));
 
-        window.draw_text(&format!("Difference: X = {:7.3}mm, Y = {:7.3}mm, Z = {:7.3}mm", 
-                (endmill_tip.translation.x - parameters[Parameter::X]) * 1000.0, 
-                (endmill_tip.translation.y - parameters[Parameter::Y]) * 1000.0, 
-                (endmill_tip.translation.z - parameters[Parameter::Z]) * 1000.0),
-            &Point2::new(0.0, 90.0), 30.0, &font, &Point3::new(1.0, 0.5, 0.5));
+            window.draw_text(&format!("Difference: X = {:7.3}mm, Y = {:7.3}mm, Z = {:7.3}mm", 
+                    (endmill_tip.translation.x - parameters[Parameter::X]) * 1000.0, 
+                    (endmill_tip.translation.y - parameters[Parameter::Y]) * 1000.0, 
+                    (endmill_tip.translation.z - parameters[Parameter::Z]) * 1000.0),
+                &Point2::new(0.0, 90.0), 30.0, &font, &Point3::new(1.0, 0.5, 0.5));
+        }
 
 
-        window.draw_text(if triggered { "Z probe: TRIGGERED" } else { "Z probe: open" }, &Point2::new(0.0, 120.0), 30.0, &font, &Point3::new(1.0, 1.0, 1.0));
+        let pairs = |collision: bool| contacts.iter().filter(|c| c.nets.is_none() == collision)
+            .map(|c| format!("{} - {}", c.probe_shape, c.object_shape)).collect::<Vec<String>>().join(", ");
+        let probe_state = if triggered {
+            let pins = calibration_object.get_inputs().into_iter().filter(|input| input.is_triggered(&contacts)).map(|input| input.pin).collect::<Vec<&str>>();
+            format!("Z probe: TRIGGERED {} ({})", pins.join(", "), pairs(false))
+        } else if !contacts.is_empty() {
+            format!("Z probe: open, COLLISION ({})", pairs(true))
+        } else if hidden {
+            "Z probe: open".to_string()
+        } else {
+            match cnc_probe.distance_to(cal_probe) {
+                Some(separation) => {
+                    gui::draw_separation(&mut window, &separation);
+                    format!("Z probe: open, gap = {:.3}mm ({} - {})", separation.distance * 1000.0, separation.probe_shape, separation.object_shape)
+                }
+                None => "Z probe: open".to_string(),
+            }
+        };
+        window.draw_text(&probe_state, &Point2::new(0.0, 120.0), 30.0, &font, &Point3::new(1.0, 1.0, 1.0));
+
+        window.draw_text(&format!("Simulated time: {:5.1} minutes, thermal growth = {:6.3}mm", 
+                gcode.get_elapsed_time() / 60.0, -gcode.get_applied_drift()[Parameter::SpindleGrowth] * 1000.0),
+            &Point2::new(0.0, 150.0), 30.0, &font, &Point3::new(1.0, 1.0, 1.0));
 
         let fps = 1.0 / (now.elapsed().as_nanos() as f64 / 1e9_f64);
         now = Instant::now();
-        window.draw_text(&format!("FPS: {:.0}", fps.round()), &Point2::new(0.0, 150.0), 30.0, &font, &Point3::new(0.5, 0.5, 0.5));
+        window.draw_text(&format!("FPS: {:.0}", fps.round()), &Point2::new(0.0, 180.0), 30.0, &font, &Point3::new(0.5, 0.5, 0.5));
+    }
+
+    if let Some(misalignment) = gcode.get_misalignment() {
+        misalignment.reveal(cnc.get_chain().get_registry());
+        export(&gcode, &parameters, &cnc);
+    }
+}
+
+fn export(gcode: &GCode, parameters: &Parameters, cnc: &MPCNC) {
+    if let Err(e) = gcode.export_urdf(parameters, cnc) {
+        panic!("Could not export URDF: {}", e);
+    }
+    if let Err(e) = gcode.export_error_map(parameters, cnc) {
+        panic!("Could not export error map: {}", e);
+    }
+}
+
+// "X:Y:Z" in mm
+fn parse_vector(text: &str) -> Result<Vec3, String> {
+    let values = text.split(':').map(|v| v.trim().parse::<f64>()).collect::<Result<Vec<f64>, _>>()
+        .map_err(|_| format!("expected three values in mm separated by colons, got {}", text))?;
+
+    match values.as_slice() {
+        [x, y, z] => Ok(Vec3::new(*x, *y, *z) / 1000.0),
+        _ => Err(format!("expected three values in mm separated by colons, got {}", text)),
     }
 }
 
-fn handle_gcode(stdin_channel: &Receiver<String>, gcode: &mut GCode, parameters: &mut Parameters<Parameter>, cnc: &MPCNC, calibration_object: &Box<dyn CalibrationObject>, fast: bool) -> bool {
+fn handle_gcode(stdin_channel: &Receiver<String>, gcode: &mut GCode, parameters: &mut Parameters, cnc: &MPCNC, calibration_object: &Box<dyn CalibrationObject>, fast: bool) -> bool {
     let mut timeout = std::time::Duration::from_millis(0);
 
     loop {
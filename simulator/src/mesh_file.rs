@@ -0,0 +1,121 @@
+use na::Point3;
+use ncollide3d::procedural::{IndexBuffer, TriMesh};
+
+use std::fs;
+use std::path::Path;
+
+// Triangles of an OBJ or STL file, used for collision shapes. Only the vertices and faces
+// are read, so this works for the same OBJ files that kiss3d renders.
+pub struct MeshFile {
+    pub vertices: Vec<Point3<f64>>,
+    pub triangles: Vec<Point3<usize>>,
+}
+
+impl MeshFile {
+    pub fn load(path: &Path, scale: f64) -> Result<MeshFile, String> {
+        let bytes = fs::read(path).map_err(|e| format!("could not read {}: {}", path.display(), e))?;
+        let extension = path.extension().and_then(|ext| ext.to_str()).map(|ext| ext.to_lowercase());
+
+        let mut mesh = match extension.as_deref() {
+            Some("obj") => parse_obj(&String::from_utf8_lossy(&bytes)),
+            Some("stl") => parse_stl(&bytes),
+            _ => Err("unknown format, expected .obj or .stl".to_string()),
+        }.and_then(|mesh| if mesh.triangles.is_empty() { Err("no triangles".to_string()) } else { Ok(mesh) })
+            .map_err(|e| format!("invalid mesh {}: {}", path.display(), e))?;
+
+        for vertex in mesh.vertices.iter_mut() {
+            *vertex *= scale;
+        }
+
+        Ok(mesh)
+    }
+
+    pub fn is_stl(path: &Path) -> bool {
+        path.extension().and_then(|ext| ext.to_str()).map(|ext| ext.to_lowercase()) == Some("stl".to_string())
+    }
+
+    // For rendering STL files, which kiss3d cannot load
+    pub fn to_trimesh(&self) -> TriMesh<f32> {
+        let coords = self.vertices.iter().map(|v| Point3::new(v.x as f32, v.y as f32, v.z as f32)).collect();
+        let indices = self.triangles.iter().map(|t| Point3::new(t.x as u32, t.y as u32, t.z as u32)).collect();
+        let mut trimesh = TriMesh::new(coords, None, None, Some(IndexBuffer::Unified(indices)));
+
+        trimesh.recompute_normals();
+        trimesh
+    }
+}
+
+// Polygons are split into triangle fans, texture coordinates and normals are ignored
+fn parse_obj(text: &str) -> Result<MeshFile, String> {
+    let mut vertices = Vec::new();
+    let mut triangles = Vec::new();
+
+    for (number, line) in text.lines().enumerate() {
+        let mut words = line.split_whitespace();
+
+        match words.next() {
+            Some("v") => {
+                let coords = words.take(3).map(|w| w.parse::<f64>()).collect::<Result<Vec<f64>, _>>()
+                    .map_err(|_| format!("invalid vertex on line {}", number + 1))?;
+                if coords.len() != 3 {
+                    return Err(format!("invalid vertex on line {}", number + 1));
+                }
+                vertices.push(Point3::new(coords[0], coords[1], coords[2]));
+            }
+            Some("f") => {
+                // indices start at 1, negative indices count back from the last vertex
+                let face = words.map(|w| match w.split('/').next().unwrap().parse::<i64>() {
+                    Ok(i) if i > 0 && i as usize <= vertices.len() => Ok(i as usize - 1),
+                    Ok(i) if i < 0 && (-i) as usize <= vertices.len() => Ok(vertices.len() - (-i) as usize),
+                    _ => Err(format!("invalid face on line {}", number + 1)),
+                }).collect::<Result<Vec<usize>, String>>()?;
+
+                for i in 2..face.len() {
+                    triangles.push(Point3::new(face[0], face[i - 1], face[i]));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Ok(MeshFile { vertices, triangles })
+}
+
+// Every facet gets its own vertices, they are not merged
+fn parse_stl(bytes: &[u8]) -> Result<MeshFile, String> {
+    let is_ascii = bytes.starts_with(b"solid") && String::from_utf8_lossy(&bytes[..bytes.len().min(1024)]).contains("facet");
+
+    let vertices = if is_ascii {
+        String::from_utf8_lossy(bytes).lines()
+            .map(|line| line.split_whitespace().collect::<Vec<&str>>())
+            .filter(|words| words.first() == Some(&"vertex"))
+            .map(|words| match words[1..].iter().map(|w| w.parse::<f64>()).collect::<Result<Vec<f64>, _>>() {
+                Ok(ref coords) if coords.len() == 3 => Ok(Point3::new(coords[0], coords[1], coords[2])),
+                _ => Err("invalid vertex".to_string()),
+            })
+            .collect::<Result<Vec<Point3<f64>>, String>>()?
+    } else {
+        // 80 byte header, the number of triangles, and 50 bytes per triangle: the normal,
+        // three vertices and an attribute
+        if bytes.len() < 84 {
+            return Err("file is too short".to_string());
+        }
+        let count = u32::from_le_bytes([bytes[80], bytes[81], bytes[82], bytes[83]]) as usize;
+        if bytes.len() < 84 + count * 50 {
+            return Err(format!("expected {} triangles", count));
+        }
+
+        let float = |offset: usize| f32::from_le_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]]) as f64;
+        (0..count * 3).map(|i| {
+            let offset = 84 + (i / 3) * 50 + 12 + (i % 3) * 12;
+            Point3::new(float(offset), float(offset + 4), float(offset + 8))
+        }).collect()
+    };
+
+    if vertices.len() % 3 != 0 {
+        return Err("facets should have three vertices".to_string());
+    }
+
+    let triangles = (0..vertices.len() / 3).map(|i| Point3::new(3 * i, 3 * i + 1, 3 * i + 2)).collect();
+    Ok(MeshFile { vertices, triangles })
+}
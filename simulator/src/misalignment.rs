@@ -0,0 +1,157 @@
+use crate::calibration_object::{self, ObjectDefinition};
+use crate::chain::{Transform, Vec3};
+use crate::mpcnc::Parameter;
+use crate::parameters::{Limits, ParameterId, ParameterInfo, ParameterRegistry, Parameters, Unit};
+
+use na::Translation3;
+use rand::Rng;
+use rand::SeedableRng;
+use rand::rngs::StdRng;
+use rand_distr::{Distribution as _, Normal};
+use std::f64::consts::PI;
+
+// The parameters set with M800-M802, which are unknown on a real machine
+const PARAMETERS: [Parameter; 7] = [
+    Parameter::ZAxisX,
+    Parameter::ZAxisY,
+    Parameter::SpindleX,
+    Parameter::SpindleY,
+    Parameter::EndmillX,
+    Parameter::EndmillY,
+    Parameter::EndmillOffset,
+];
+
+// How far each calibration object is from where it should be, like when it is put down by hand.
+// The default standard deviations are in mm or degrees.
+const OBJECT_POSE: [(&str, Unit, f64); 6] = [
+    ("ObjectX", Unit::Length, 1.0),
+    ("ObjectY", Unit::Length, 1.0),
+    ("ObjectZ", Unit::Length, 0.0),
+    ("ObjectRotation", Unit::Angle, 1.0),
+    ("ObjectTiltX", Unit::Angle, 0.05),
+    ("ObjectTiltY", Unit::Angle, 0.05),
+];
+
+// In mm or degrees, like M503
+#[derive(Copy, Clone)]
+pub enum Distribution {
+    Normal(f64, f64),
+    Uniform(f64, f64),
+}
+
+// Drawn once at startup from a seed, so a session can be repeated. The values are hidden from
+// M503 and the GUI until the simulator exits, for blind tests of the estimator.
+pub struct Misalignment {
+    seed: u64,
+    parameters: Vec<(ParameterId, f64)>,
+    objects: Vec<(String, Vec<f64>)>,
+}
+
+// "NAME=normal:MEAN:STDDEV" or "NAME=uniform:MIN:MAX"
+pub fn parse_distribution(text: &str) -> Result<(String, Distribution), String> {
+    let error = || format!("expected NAME=normal:MEAN:STDDEV or NAME=uniform:MIN:MAX, got {}", text);
+    let (name, distribution) = match text.find('=') {
+        Some(i) => (text[..i].trim(), &text[i + 1..]),
+        None => return Err(error()),
+    };
+
+    let fields = distribution.split(':').collect::<Vec<&str>>();
+    let values = fields[1..].iter().map(|v| v.trim().parse::<f64>()).collect::<Result<Vec<f64>, _>>().map_err(|_| error())?;
+
+    match (fields[0].trim(), values.as_slice()) {
+        ("normal", [mean, stddev]) if *stddev >= 0.0 => Ok((name.to_string(), Distribution::Normal(*mean, *stddev))),
+        ("uniform", [min, max]) if min <= max => Ok((name.to_string(), Distribution::Uniform(*min, *max))),
+        _ => Err(error()),
+    }
+}
+
+impl Distribution {
+    fn sample(&self, rng: &mut StdRng) -> f64 {
+        match *self {
+            Distribution::Normal(mean, stddev) => Normal::new(mean, stddev).unwrap().sample(rng),
+            Distribution::Uniform(min, max) if max > min => rng.gen_range(min, max),
+            Distribution::Uniform(min, _) => min,
+        }
+    }
+}
+
+// Normal around the default, with the stddev from the machine file or 0.1 degrees or mm. The
+// end mill offset is the length of the arm, which can't be negative: a normal around its default
+// of 0 would be clamped to exactly 0 for half of the seeds, so it is uniform between its limits.
+fn get_default_distribution(param: Parameter, info: &ParameterInfo) -> Distribution {
+    match (param, info.limits) {
+        (Parameter::EndmillOffset, Limits::Range(min, max)) if min.is_finite() && max.is_finite() => {
+            Distribution::Uniform(info.unit.to_display(min), info.unit.to_display(max))
+        }
+        _ => {
+            let stddev = if info.stddev > 0.0 { info.unit.to_display(info.stddev) } else { 0.1 };
+            Distribution::Normal(info.unit.to_display(info.default), stddev)
+        }
+    }
+}
+
+impl Misalignment {
+    // Parameters without a distribution get the default one, and the objects are moved from
+    // where they were placed.
+    pub fn draw(seed: u64, registry: &ParameterRegistry, distributions: &[(String, Distribution)], objects: &mut [(Option<String>, ObjectDefinition)]) -> Result<Misalignment, String> {
+        let names = PARAMETERS.iter().map(|param| param.name()).chain(OBJECT_POSE.iter().map(|(name, _, _)| *name)).collect::<Vec<&str>>();
+        if let Some((name, _)) = distributions.iter().find(|(name, _)| !names.contains(&name.as_str())) {
+            return Err(format!("unknown parameter {}, expected one of {}", name, names.join(", ")));
+        }
+
+        let distribution = |name: &str, default: Distribution| distributions.iter().rev()
+            .find(|(n, _)| n == name).map_or(default, |(_, distribution)| *distribution);
+        let mut rng = StdRng::seed_from_u64(seed);
+
+        let parameters = PARAMETERS.iter().map(|param| {
+            let id = ParameterId::from(*param);
+            let info = registry.get(id);
+            let value = distribution(param.name(), get_default_distribution(*param, info)).sample(&mut rng);
+
+            (id, registry.bounded(id, info.unit.to_internal(value)))
+        }).collect();
+
+        let mut drawn = Vec::new();
+        for (i, (name, object)) in objects.iter_mut().enumerate() {
+            if let Some(pos) = object.get_pose_mut() {
+                let offset = OBJECT_POSE.iter()
+                    .map(|(name, unit, stddev)| unit.to_internal(distribution(name, Distribution::Normal(0.0, *stddev)).sample(&mut rng)))
+                    .collect::<Vec<f64>>();
+
+                let pose = calibration_object::get_pose(&Vec3::new(offset[0], offset[1], offset[2]), offset[3], offset[4], offset[5]);
+                *pos = Transform::from_parts(Translation3::from(pos.translation.vector + pose.translation.vector), pose.rotation * pos.rotation);
+
+                drawn.push((name.clone().unwrap_or_else(|| format!("object {}", i + 1)), offset));
+            }
+        }
+
+        Ok(Misalignment { seed, parameters, objects: drawn })
+    }
+
+    pub fn apply(&self, parameters: &mut Parameters) {
+        for (id, value) in self.parameters.iter() {
+            parameters[*id] = *value;
+        }
+    }
+
+    pub fn is_hidden(&self, id: ParameterId) -> bool {
+        self.parameters.iter().any(|(hidden, _)| *hidden == id)
+    }
+
+    pub fn reveal(&self, registry: &ParameterRegistry) {
+        println!("echo:Misalignment drawn with seed {}:", self.seed);
+
+        // angles are wrapped to 0..360 degrees, but small negative ones are easier to compare
+        for (id, value) in self.parameters.iter() {
+            let info = registry.get(*id);
+            let value = if info.unit == Unit::Angle && *value > PI { value - 2.0 * PI } else { *value };
+            println!("echo:{} = {:.4} {}", info.name, info.unit.to_display(value), info.unit.get_display_name());
+        }
+        for (name, offset) in self.objects.iter() {
+            let values = OBJECT_POSE.iter().zip(offset.iter())
+                .map(|((pose, unit, _), value)| format!("{} = {:.4} {}", pose, unit.to_display(*value), unit.get_display_name()))
+                .collect::<Vec<String>>();
+            println!("echo:{}: {}", name, values.join(", "));
+        }
+    }
+}
@@ -1,11 +1,18 @@
-use crate::chain::{Bounds, Chain, FixedLink, Parameters, RotatingLink, SlidingLink, Transform, Vec3};
+use crate::chain::{Chain, IkSolution, KinematicsCache, PoseCovariance, Transform, Vec3};
 use crate::gui::{draw_transform};
-use crate::probe::Probe;
+use crate::machine::{MachineDefinition, MaterialDefinition};
+use crate::mesh_file::MeshFile;
+use crate::parameters::{ParameterId, ParameterInfo, ParameterRegistry, Parameters, Unit};
+use crate::probe::{Material, Probe, PROBE_NET};
+use crate::urdf::{self, Visual};
 
+use ncollide3d::procedural::TriMesh;
 use kiss3d::scene::SceneNode;
+use std::cell::RefCell;
 use kiss3d::window::Window;
+use std::ops::{Index, IndexMut};
 use std::path::Path;
-use enum_map::{Enum, EnumMap};
+use enum_map::Enum;
 use na::{Translation3, UnitQuaternion};
 use ncollide3d::shape::ShapeHandle;
 
@@ -20,170 +27,314 @@ pub enum Parameter {
     ZAxisY,
     SpindleX,
     SpindleY,
+    SpindleGrowth,
     EndmillX,
     EndmillY,
     EndmillOffset,
 }
 
-impl Bounds<Parameter> for Parameter {
-    fn bounded(&self, new_value: f64) -> f64 {
+impl Parameter {
+    pub fn name(&self) -> &'static str {
         match self {
-            Parameter::X => new_value.max(0.0).min(1.0),
-            Parameter::Y => new_value.max(0.0).min(0.5),
-            Parameter::Z => new_value.max(-0.045).min(0.0),
-            Parameter::EndmillOffset => new_value.max(0.0).min(0.160),
-            _ => (new_value + std::f64::consts::PI * 2.0) % (std::f64::consts::PI * 2.0),
+            Parameter::X => "X",
+            Parameter::Y => "Y",
+            Parameter::Z => "Z",
+            Parameter::Spindle => "Spindle",
+            Parameter::ZAxisX => "ZAxisX",
+            Parameter::ZAxisY => "ZAxisY",
+            Parameter::SpindleX => "SpindleX",
+            Parameter::SpindleY => "SpindleY",
+            Parameter::SpindleGrowth => "SpindleGrowth",
+            Parameter::EndmillX => "EndmillX",
+            Parameter::EndmillY => "EndmillY",
+            Parameter::EndmillOffset => "EndmillOffset",
+        }
+    }
+
+    pub fn get_unit(&self) -> Unit {
+        match self {
+            Parameter::X | Parameter::Y | Parameter::Z | Parameter::SpindleGrowth | Parameter::EndmillOffset => Unit::Length,
+            _ => Unit::Angle,
+        }
+    }
+
+    pub fn all() -> impl Iterator<Item = Parameter> {
+        (0..<Parameter as Enum<f64>>::POSSIBLE_VALUES).map(<Parameter as Enum<f64>>::from_usize)
+    }
+
+    // The built-in parameters always come first in the registry, in the order of the enum
+    pub fn create_registry() -> ParameterRegistry {
+        let mut registry = ParameterRegistry::new();
+
+        for param in Parameter::all() {
+            let id = registry.add(ParameterInfo::new(param.name(), param.get_unit()));
+            assert_eq!(id, ParameterId::from(param));
+        }
+
+        registry
+    }
+}
+
+impl From<Parameter> for ParameterId {
+    fn from(param: Parameter) -> ParameterId {
+        ParameterId(<Parameter as Enum<f64>>::to_usize(param))
+    }
+}
+
+impl Index<Parameter> for Parameters {
+    type Output = f64;
+
+    fn index(&self, param: Parameter) -> &f64 {
+        &self[ParameterId::from(param)]
+    }
+}
+
+impl IndexMut<Parameter> for Parameters {
+    fn index_mut(&mut self, param: Parameter) -> &mut f64 {
+        &mut self[ParameterId::from(param)]
+    }
+}
+
+struct Mesh {
+    node: SceneNode,
+    frame: usize,
+    stationary: Vec3,
+    collision: Option<(&'static str, Material, ShapeHandle<f64>)>,
+}
+
+// All tools are 4mm in diameter and stick out 30mm from the collet, the tip is at the origin
+#[derive(Copy, Clone)]
+pub enum Tool {
+    Endmill,
+    BallEnd,
+    VBit,
+    TouchProbe,
+}
+
+impl Tool {
+    pub fn from_name(name: &str) -> Option<Tool> {
+        match name {
+            "endmill" => Some(Tool::Endmill),
+            "ball-end" => Some(Tool::BallEnd),
+            "v-bit" => Some(Tool::VBit),
+            "touch-probe" => Some(Tool::TouchProbe),
+            _ => None,
+        }
+    }
+
+    // The tool is wired to the probe circuit, a touch probe triggers on any contact
+    fn get_material(self) -> Material {
+        match self {
+            Tool::TouchProbe => Material::Switch(PROBE_NET),
+            _ => Material::Conductive(Some(PROBE_NET)),
+        }
+    }
+
+    fn get_parts(self) -> Vec<(&'static str, TriMesh<f32>, ShapeHandle<f64>)> {
+        let diameter = 0.004;
+        let length = 0.030;
+        let vertical = |z: f64| Transform::from_parts(Translation3::new(0.0, 0.0, z), UnitQuaternion::from_axis_angle(&Vec3::x_axis(), 90.0_f64.to_radians()));
+        let shank = |from: f64| {
+            let (trimesh, shape) = Probe::get_cylinder_shape(diameter, length - from, &vertical((length + from) / 2.0));
+            ("shank", trimesh, shape)
+        };
+
+        match self {
+            Tool::Endmill => {
+                // a thin tip, so the contact point is well defined
+                let (tip_diameter, tip_length) = (0.0002, 0.0005);
+                let (trimesh, shape) = Probe::get_cylinder_shape(tip_diameter, tip_length, &vertical(tip_length / 2.0));
+                vec![shank(tip_length), ("tip", trimesh, shape)]
+            }
+            Tool::BallEnd => {
+                let radius = diameter / 2.0;
+                let (trimesh, shape) = Probe::get_sphere_shape(diameter, &Transform::translation(0.0, 0.0, radius));
+                vec![shank(radius), ("tip", trimesh, shape)]
+            }
+            Tool::VBit => {
+                // 60 degree cone with the apex pointing down
+                let height = diameter / 2.0 / 30.0_f64.to_radians().tan();
+                let (trimesh, shape) = Probe::get_cone_shape(diameter, height, &Transform::from_parts(
+                    Translation3::new(0.0, 0.0, height / 2.0),
+                    UnitQuaternion::from_axis_angle(&Vec3::x_axis(), -90.0_f64.to_radians())
+                ));
+                vec![shank(height), ("tip", trimesh, shape)]
+            }
+            Tool::TouchProbe => {
+                // 2mm ruby ball on a 1mm stylus
+                let (ball, stylus) = (0.002, 0.001);
+                let (ball_trimesh, ball_shape) = Probe::get_sphere_shape(ball, &Transform::translation(0.0, 0.0, ball / 2.0));
+                let (stylus_trimesh, stylus_shape) = Probe::get_capsule_shape(stylus, length - ball, &vertical((length + ball) / 2.0));
+                vec![("shank", stylus_trimesh, stylus_shape), ("tip", ball_trimesh, ball_shape)]
+            }
         }
     }
 }
 
 pub struct MPCNC {
-    frame: SceneNode,
-    spoilboard: SceneNode,
-    x_tube: SceneNode,
-    y_tube: SceneNode,
-    z_axis: SceneNode,
-    spindle: SceneNode,
-    arm: SceneNode,
-    endmill: SceneNode,
-    endmill_tip: SceneNode,
-    endmill_collision_shape: ShapeHandle<f64>,
-    endmill_tip_collision_shape: ShapeHandle<f64>,
-    endmill_index: usize,
-    chain: Chain<Parameter>,
+    name: String,
+    meshes: Vec<Mesh>,
+    visuals: Vec<Visual>,
+    tool: Tool,
+    tool_parts: Vec<(&'static str, SceneNode, ShapeHandle<f64>)>,
+    tool_tip_frame: usize,
+    chain: Chain,
+    cache: RefCell<KinematicsCache>,
+}
+
+impl Mesh {
+    fn get_pose(&self, frames: &[Transform]) -> Transform {
+        let pose = frames[self.frame];
+        let offset = pose.inverse_transform_vector(&-pose.translation.vector.component_mul(&self.stationary));
+
+        pose * Transform::translation(offset.x, offset.y, offset.z)
+    }
 }
 
 impl MPCNC {
-    pub fn new(window: &mut Window, resources_dir: &Path) -> MPCNC {
-        let mm = na::Vector3::new(0.001, 0.001, 0.001);
-
-        let base_link = FixedLink::new(&Transform::translation(0.0, 0.0, 0.14));
-        let x_link = SlidingLink::new(&Vec3::x_axis(), Parameter::X);
-        let y_link = SlidingLink::new(&Vec3::y_axis(), Parameter::Y);
-        let z_link = SlidingLink::new(&Vec3::z_axis(), Parameter::Z);
-
-        let z_axis_offset_link = FixedLink::new(&Transform::translation(-0.09, 0.09, 0.0));
-        let z_axis_x_link = RotatingLink::new(&Vec3::x_axis(), 0.0_f64.to_radians(), Parameter::ZAxisX);
-        let z_axis_y_link = RotatingLink::new(&Vec3::y_axis(), 0.0_f64.to_radians(), Parameter::ZAxisY);
-        let z_axis_offset_inv_link = FixedLink::new(&Transform::translation(0.09, -0.09, -0.05));
-
-        let spindle_offset_link = FixedLink::new(&Transform::translation(0.0, 0.0, -0.01 + 0.185 / 2.0));
-        let spindle_x_link = RotatingLink::new(&Vec3::x_axis(), 0.0_f64.to_radians(), Parameter::SpindleX);
-        let spindle_y_link = RotatingLink::new(&Vec3::y_axis(), 0.0_f64.to_radians(), Parameter::SpindleY);
-        let spindle_rotation_link = RotatingLink::new(&Vec3::z_axis(), 0.0_f64.to_radians(), Parameter::Spindle);
-        let spindle_offset_inv_link = FixedLink::new(&Transform::translation(0.0, 0.0, -0.185 / 2.0));
-
-        let endmill_offset_link = SlidingLink::new(&Vec3::x_axis(), Parameter::EndmillOffset);
-        let endmill_x_link = RotatingLink::new(&Vec3::x_axis(), 0.0_f64.to_radians(), Parameter::EndmillX);
-        let endmill_y_link = RotatingLink::new(&Vec3::y_axis(), 0.0_f64.to_radians(), Parameter::EndmillY);
-        let endmill_tip_link = FixedLink::new(&Transform::translation(0.0, 0.0, -0.03));
-        let end_effector_link = FixedLink::new(&Transform::translation(0.0, 0.0, 0.0));
-
-        let chain = Chain::new(vec![
-            Box::new(base_link),
-            Box::new(x_link),
-            Box::new(y_link),
-            Box::new(z_axis_offset_link),
-            Box::new(z_axis_x_link),
-            Box::new(z_axis_y_link),
-            Box::new(z_link),
-            Box::new(z_axis_offset_inv_link),
-            Box::new(spindle_offset_link),
-            Box::new(spindle_x_link),
-            Box::new(spindle_y_link),
-            Box::new(spindle_rotation_link),
-            Box::new(spindle_offset_inv_link),
-            Box::new(endmill_offset_link),
-            Box::new(endmill_x_link),
-            Box::new(endmill_y_link),
-            Box::new(endmill_tip_link),
-            Box::new(end_effector_link),
-        ]);
-
-        let tip_diam   = 0.0002;
-        let tip_length = 0.0005;
-        let (endmill_trimesh, endmill_collision_shape) = Probe::get_cylinder_shape(0.004, 0.030 - tip_length, &Transform::from_parts(
-            Translation3::new(0.0, 0.0, (0.030 - tip_length) / 2.0 + tip_length), 
-            UnitQuaternion::from_axis_angle(&Vec3::x_axis(), 90.0_f64.to_radians())
-        ));
-        let (endmill_tip_trimesh, endmill_tip_collision_shape) = Probe::get_cylinder_shape(tip_diam, tip_length, &Transform::from_parts(
-            Translation3::new(0.0, 0.0, tip_length / 2.0), 
-            UnitQuaternion::from_axis_angle(&Vec3::x_axis(), 90.0_f64.to_radians())
-        ));
-
-        let mut mpcnc = MPCNC {
-            frame: window.add_obj(&resources_dir.join("frame.obj"), resources_dir, mm),
-            spoilboard: window.add_obj(&resources_dir.join("spoilboard.obj"), resources_dir, mm),
-            x_tube: window.add_obj(&resources_dir.join("gantry-x-tube.obj"), resources_dir, mm),
-            y_tube: window.add_obj(&resources_dir.join("gantry-y-tube.obj"), resources_dir, mm),
-            z_axis: window.add_obj(&resources_dir.join("z-axis.obj"), resources_dir, mm),
-            spindle: window.add_obj(&resources_dir.join("spindle.obj"), resources_dir, mm),
-            arm: window.add_obj(&resources_dir.join("arm.obj"), resources_dir, mm),
-            endmill: window.add_trimesh(endmill_trimesh, na::Vector3::from_element(1.0_f32)),
-            endmill_tip: window.add_trimesh(endmill_tip_trimesh, na::Vector3::from_element(1.0_f32)),
-            endmill_collision_shape: endmill_collision_shape,
-            endmill_tip_collision_shape: endmill_tip_collision_shape,
-            endmill_index: 17,
-            chain: chain,
-        };
+    pub fn new(window: &mut Window, resources_dir: &Path, machine: &MachineDefinition, tool: Tool) -> MPCNC {
+        let chain = machine.build_chain();
+        let meshes = machine.meshes.iter().map(|mesh| {
+            let file = resources_dir.join(&mesh.file);
+            let scale = na::Vector3::from_element(mesh.scale);
+            let triangles = if mesh.collision || MeshFile::is_stl(&file) {
+                Some(MeshFile::load(&file, mesh.scale as f64).unwrap_or_else(|e| panic!("Could not load mesh: {}", e)))
+            } else {
+                None
+            };
+
+            let mut node = match &triangles {
+                Some(triangles) if MeshFile::is_stl(&file) => window.add_trimesh(triangles.to_trimesh(), na::Vector3::from_element(1.0_f32)),
+                _ => window.add_obj(&file, resources_dir, scale),
+            };
+            node.set_color(mesh.color[0], mesh.color[1], mesh.color[2]);
 
-        mpcnc.frame.set_color(0.5, 0.5, 0.5);
-        mpcnc.spoilboard.set_color(0.25, 0.25, 0.25);
-        mpcnc.x_tube.set_color(0.5, 0.5, 0.5);
-        mpcnc.y_tube.set_color(0.5, 0.5, 0.5);
-        mpcnc.z_axis.set_color(0.0, 0.0, 1.0);
-        mpcnc.spindle.set_color(0.0, 1.0, 0.0);
-        mpcnc.arm.set_color(0.0, 1.0, 0.0);
-        mpcnc.endmill.set_color(1.0, 0.0, 0.0);
-        mpcnc.endmill_tip.set_color(1.0, 0.0, 0.0);
+            // the machine is loaded once, so the names can live as long as the program
+            let leak = |name: String| -> &'static str { Box::leak(name.into_boxed_str()) };
+            let material = match mesh.material {
+                MaterialDefinition::Insulating => Material::Insulating,
+                MaterialDefinition::Conductive => Material::Conductive(mesh.net.clone().map(leak)),
+            };
+            let collision = triangles.filter(|_| mesh.collision).map(|triangles| {
+                let name = leak(file.file_stem().unwrap().to_string_lossy().into_owned());
+                (name, material, Probe::get_mesh_shape(&triangles, &Transform::identity()))
+            });
 
-        mpcnc
+            Mesh {
+                node,
+                collision,
+                frame: chain.get_frame(&mesh.frame).expect("Unknown frame"),
+                stationary: Vec3::new(
+                    if mesh.stationary_axes.iter().any(|a| a == "x") { 1.0 } else { 0.0 },
+                    if mesh.stationary_axes.iter().any(|a| a == "y") { 1.0 } else { 0.0 },
+                    if mesh.stationary_axes.iter().any(|a| a == "z") { 1.0 } else { 0.0 },
+                ),
+            }
+        }).collect();
+        let visuals = machine.meshes.iter().map(|mesh| {
+            let file = resources_dir.join(&mesh.file);
+
+            Visual {
+                file: file.canonicalize().unwrap_or(file),
+                frame: chain.get_frame(&mesh.frame).expect("Unknown frame"),
+                scale: mesh.scale,
+                color: mesh.color,
+            }
+        }).collect();
+
+        MPCNC {
+            name: machine.name.clone(),
+            meshes,
+            visuals,
+            tool,
+            tool_parts: tool.get_parts().into_iter().map(|(name, trimesh, shape)| {
+                let mut node = window.add_trimesh(trimesh, na::Vector3::from_element(1.0_f32));
+                node.set_color(1.0, 0.0, 0.0);
+                (name, node, shape)
+            }).collect(),
+            tool_tip_frame: chain.get_frame("tool_tip").expect("Unknown frame"),
+            cache: RefCell::new(chain.create_cache()),
+            chain,
+        }
     }
 
-    #[allow(dead_code)]
-    pub fn get_chain(&self) -> &Chain<Parameter> {
+    pub fn get_chain(&self) -> &Chain {
         &self.chain
     }
 
-    pub fn get_end_effector_pos(&self, parameters: &Parameters<Parameter>) -> Transform {
-        self.chain.compute_all_start_poses(parameters)[self.endmill_index]
+    pub fn get_end_effector_pos(&self, parameters: &Parameters) -> Transform {
+        self.get_frame_pose(parameters, self.tool_tip_frame)
     }
 
-    pub fn get_probe(&self, parameters: &Parameters<Parameter>) -> Probe {
-        Probe::new(vec![
-            (self.get_end_effector_pos(parameters), self.endmill_collision_shape.clone()),
-            (self.get_end_effector_pos(parameters), self.endmill_tip_collision_shape.clone())
-        ])
+    fn get_frame_pose(&self, parameters: &Parameters, frame: usize) -> Transform {
+        self.chain.update_cache(&mut self.cache.borrow_mut(), parameters)[frame]
     }
 
-    pub fn get_default_parameters(&self) -> Parameters<Parameter> {
-        let mut params = EnumMap::new();
+    // Stepper positions (and optionally the spindle angle) that put the tip of the end mill at `target`
+    pub fn solve_end_effector_pos(&self, parameters: &Parameters, target: &Vec3, rotate_spindle: bool) -> IkSolution {
+        let free = if rotate_spindle {
+            vec![Parameter::X.into(), Parameter::Y.into(), Parameter::Z.into(), Parameter::Spindle.into()]
+        } else {
+            vec![Parameter::X.into(), Parameter::Y.into(), Parameter::Z.into()]
+        };
 
-        params[Parameter::X] = 0.50;
-        params[Parameter::Y] = 0.25;
-        params
+        self.chain.solve_position(parameters, self.tool_tip_frame, target, &free, 1e-8, 100)
     }
 
-    pub fn render(&mut self, window: &mut Window, parameters: &Parameters<Parameter>, show_transforms: bool) {
-        if show_transforms {
-            let end_poses = self.chain.compute_all_end_poses(parameters);
+    // Uses the standard errors of the parameters in the machine description
+    pub fn get_end_effector_covariance(&self, parameters: &Parameters) -> PoseCovariance {
+        self.chain.compute_covariance(parameters, self.tool_tip_frame, &self.chain.get_registry().get_covariance())
+    }
+
+    // The tool and the parts of the machine that move with it
+    pub fn get_probe(&self, parameters: &Parameters) -> Probe {
+        let mut cache = self.cache.borrow_mut();
+        let frames = self.chain.update_cache(&mut cache, parameters);
+        let pose = frames[self.tool_tip_frame];
+
+        let parts = self.meshes.iter().filter(|mesh| mesh.frame != 0);
+        Probe::new(self.tool_parts.iter().map(|(name, _, shape)| (*name, self.tool.get_material(), pose, shape.clone()))
+            .chain(parts.filter_map(|mesh| mesh.collision.as_ref().map(|(name, material, shape)| (*name, *material, mesh.get_pose(frames), shape.clone()))))
+            .collect())
+    }
+
+    // The parts of the machine that are attached to the world, like the frame. The probe can
+    // collide with them, just like with the calibration object.
+    pub fn get_fixed_probe(&self) -> Probe {
+        let parts = self.meshes.iter().filter(|mesh| mesh.frame == 0);
+
+        Probe::new(parts.filter_map(|mesh| mesh.collision.as_ref().map(|(name, material, shape)| (*name, *material, Transform::identity(), shape.clone()))).collect())
+    }
+
+    pub fn to_urdf(&self, parameters: &Parameters) -> String {
+        urdf::export(&self.name, &self.chain, parameters, &self.visuals)
+    }
 
-            for pose in end_poses.iter() {
+    pub fn get_default_parameters(&self) -> Parameters {
+        self.chain.get_registry().get_default_parameters()
+    }
+
+    pub fn bounded(&self, parameter: Parameter, new_value: f64) -> f64 {
+        self.chain.bounded(parameter.into(), new_value)
+    }
+
+    pub fn render(&mut self, window: &mut Window, parameters: &Parameters, show_transforms: bool) {
+        let mut cache = self.cache.borrow_mut();
+        let frames = self.chain.update_cache(&mut cache, parameters);
+
+        if show_transforms {
+            for pose in frames[1..].iter() {
                 draw_transform(window, pose, 0.1);
             }
         }
 
-        let start_poses = self.chain.compute_all_start_poses(parameters);
-
-        self.frame.set_local_transformation(na::convert(start_poses[0]));
-        self.spoilboard.set_local_transformation(na::convert(start_poses[0]));
-        self.x_tube.set_local_transformation(na::convert(start_poses[2]));
-        self.y_tube.set_local_transformation(na::convert(start_poses[3] * Transform::translation(-start_poses[3].translation.x, 0.0, 0.0)));
-        self.z_axis.set_local_transformation(na::convert(start_poses[8]));
-        self.spindle.set_local_transformation(na::convert(start_poses[13]));
-        self.arm.set_local_transformation(na::convert(start_poses[13]));
+        for mesh in self.meshes.iter_mut() {
+            let pose = mesh.get_pose(frames);
+            mesh.node.set_local_transformation(na::convert(pose));
+        }
         
-        self.endmill.set_local_transformation(na::convert(self.get_end_effector_pos(parameters)));
-        self.endmill_tip.set_local_transformation(na::convert(self.get_end_effector_pos(parameters)));
+        for (_, node, _) in self.tool_parts.iter_mut() {
+            node.set_local_transformation(na::convert(frames[self.tool_tip_frame]));
+        }
     }
 }
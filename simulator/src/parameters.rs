@@ -0,0 +1,155 @@
+use std::ops::{Index, IndexMut};
+
+use na::{DMatrix, DVector};
+
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub struct ParameterId(pub usize);
+
+// Lengths are stored in meters and angles in radians
+#[derive(Copy, Clone, PartialEq)]
+pub enum Unit {
+    Length,
+    Angle,
+    Scalar,
+}
+
+#[derive(Copy, Clone)]
+pub enum Limits {
+    Unbounded,
+    Range(f64, f64),
+    Angle,
+}
+
+pub struct ParameterInfo {
+    pub name: String,
+    pub unit: Unit,
+    pub limits: Limits,
+    pub default: f64,
+    pub hidden: bool,
+    // standard error of the value, in the same unit as the value
+    pub stddev: f64,
+}
+
+pub struct ParameterRegistry {
+    parameters: Vec<ParameterInfo>,
+}
+
+#[derive(Clone)]
+pub struct Parameters {
+    values: Vec<f64>,
+}
+
+impl Unit {
+    pub fn get_display_name(self) -> &'static str {
+        match self {
+            Unit::Length => "mm",
+            Unit::Angle => "degrees",
+            Unit::Scalar => "",
+        }
+    }
+
+    pub fn to_display(self, value: f64) -> f64 {
+        match self {
+            Unit::Length => value * 1000.0,
+            Unit::Angle => value.to_degrees(),
+            Unit::Scalar => value,
+        }
+    }
+
+    pub fn to_internal(self, value: f64) -> f64 {
+        match self {
+            Unit::Length => value / 1000.0,
+            Unit::Angle => value.to_radians(),
+            Unit::Scalar => value,
+        }
+    }
+}
+
+impl Limits {
+    pub fn apply(&self, new_value: f64) -> f64 {
+        match self {
+            Limits::Unbounded => new_value,
+            Limits::Range(min, max) => new_value.max(*min).min(*max),
+            Limits::Angle => (new_value + std::f64::consts::PI * 2.0) % (std::f64::consts::PI * 2.0),
+        }
+    }
+}
+
+impl ParameterInfo {
+    pub fn new(name: &str, unit: Unit) -> ParameterInfo {
+        ParameterInfo {
+            name: name.to_string(),
+            unit,
+            limits: if unit == Unit::Angle { Limits::Angle } else { Limits::Unbounded },
+            default: 0.0,
+            hidden: false,
+            stddev: 0.0,
+        }
+    }
+}
+
+impl ParameterRegistry {
+    pub fn new() -> ParameterRegistry {
+        ParameterRegistry { parameters: Vec::new() }
+    }
+
+    pub fn add(&mut self, info: ParameterInfo) -> ParameterId {
+        assert!(self.find(&info.name).is_none(), "Parameter {} is defined more than once", info.name);
+
+        self.parameters.push(info);
+        ParameterId(self.parameters.len() - 1)
+    }
+
+    pub fn find(&self, name: &str) -> Option<ParameterId> {
+        self.parameters.iter().position(|info| info.name == name).map(ParameterId)
+    }
+
+    pub fn get(&self, id: ParameterId) -> &ParameterInfo {
+        &self.parameters[id.0]
+    }
+
+    pub fn get_mut(&mut self, id: ParameterId) -> &mut ParameterInfo {
+        &mut self.parameters[id.0]
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (ParameterId, &ParameterInfo)> {
+        self.parameters.iter().enumerate().map(|(i, info)| (ParameterId(i), info))
+    }
+
+    pub fn bounded(&self, id: ParameterId, new_value: f64) -> f64 {
+        self.parameters[id.0].limits.apply(new_value)
+    }
+
+    // Assumes the errors of the parameters are independent
+    pub fn get_covariance(&self) -> DMatrix<f64> {
+        let variances = DVector::from_iterator(self.parameters.len(), self.parameters.iter().map(|info| info.stddev * info.stddev));
+
+        DMatrix::from_diagonal(&variances)
+    }
+
+    pub fn get_default_parameters(&self) -> Parameters {
+        Parameters {
+            values: self.parameters.iter().map(|info| info.default).collect(),
+        }
+    }
+}
+
+impl Parameters {
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+}
+
+impl Index<ParameterId> for Parameters {
+    type Output = f64;
+
+    fn index(&self, id: ParameterId) -> &f64 {
+        &self.values[id.0]
+    }
+}
+
+impl IndexMut<ParameterId> for Parameters {
+    fn index_mut(&mut self, id: ParameterId) -> &mut f64 {
+        &mut self.values[id.0]
+    }
+}
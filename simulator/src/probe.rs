@@ -1,99 +1,591 @@
 use crate::chain::{Transform, Vec3};
+use crate::mesh_file::MeshFile;
 
-use ncollide3d::shape::{ShapeHandle, ConvexHull};
+use na::{Point3, Unit};
+use ncollide3d::bounding_volume::{self, AABB};
+use ncollide3d::partitioning::{BVTNodeId, BVH};
+use ncollide3d::shape::{Ball, Capsule, Cone, Cuboid, Cylinder, FeatureId, Shape, ShapeHandle, SupportMap};
 use ncollide3d::procedural::TriMesh;
+use ncollide3d::query::ClosestPoints;
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap};
 
+// Each shape has a name, so contacts can tell which shapes are touching
 pub struct Probe {
-    objects: Vec<(Transform, ShapeHandle<f64>)>,
+    objects: Vec<(&'static str, Material, Transform, ShapeHandle<f64>)>,
+}
+
+// The probe is triggered when a contact connects two nets of the probe circuit. Conductive
+// parts that are not wired to the circuit have no net, and touching them is a collision.
+#[derive(Copy, Clone, PartialEq)]
+pub enum Material {
+    Insulating,
+    Conductive(Option<&'static str>),
+    // closes its net on any contact, like the stylus of a touch probe
+    Switch(&'static str),
+}
+
+// The net of the tool in the collet
+pub const PROBE_NET: &str = "probe";
+
+// New names for shapes, nets and pins, names that are not in it stay the same
+pub type Names = HashMap<&'static str, &'static str>;
+
+// An input pin of the controller, wired to some nets of the probe circuit. It is triggered by
+// contacts that connect one of them.
+pub struct ProbeInput {
+    pub pin: &'static str,
+    nets: Vec<&'static str>,
+}
+
+// The point is on the shape of the other probe and the normal points from this probe towards
+// the other, both in world coordinates. The depth is negative when the shapes are not touching.
+// A contact without nets is a collision that does not trigger the probe.
+pub struct Contact {
+    pub probe_shape: &'static str,
+    pub object_shape: &'static str,
+    pub nets: Option<(&'static str, &'static str)>,
+    pub point: Vec3,
+    pub normal: Vec3,
+    pub depth: f64,
+}
+
+// Closest points on the nearest pair of shapes, in world coordinates. The distance is
+// negative when the shapes are penetrating, then the points are the deepest points.
+pub struct Separation {
+    pub probe_shape: &'static str,
+    pub object_shape: &'static str,
+    pub distance: f64,
+    pub probe_point: Vec3,
+    pub object_point: Vec3,
+}
+
+// An exact convex primitive, moved by a transform within its probe object. ncollide only
+// accepts some primitives as shapes, but all of them as support maps.
+#[derive(Clone)]
+struct PlacedShape<S> {
+    transform: Transform,
+    shape: S,
+}
+
+impl<S: SupportMap<f64>> SupportMap<f64> for PlacedShape<S> {
+    fn support_point(&self, m: &Transform, dir: &Vec3) -> Point3<f64> {
+        self.shape.support_point(&(m * self.transform), dir)
+    }
+}
+
+impl<S: SupportMap<f64> + Clone + Send + Sync + 'static> Shape<f64> for PlacedShape<S> {
+    fn aabb(&self, m: &Transform) -> AABB<f64> {
+        bounding_volume::support_map_aabb(m, self)
+    }
+
+    fn tangent_cone_contains_dir(&self, _feature: FeatureId, _m: &Transform, _deformations: Option<&[f64]>, _dir: &Unit<Vec3>) -> bool {
+        false
+    }
+
+    fn as_support_map(&self) -> Option<&dyn SupportMap<f64>> {
+        Some(self)
+    }
+
+    fn is_support_map(&self) -> bool {
+        true
+    }
+}
+
+impl Material {
+    // The nets that are connected when shapes of these materials touch
+    fn connect(self, other: Material) -> Option<(&'static str, &'static str)> {
+        match (self, other) {
+            (Material::Switch(net), _) | (_, Material::Switch(net)) => Some((net, net)),
+            (Material::Conductive(Some(a)), Material::Conductive(Some(b))) if a != b => Some((a, b)),
+            _ => None,
+        }
+    }
+}
+
+impl ProbeInput {
+    pub fn new(pin: &'static str, nets: Vec<&'static str>) -> ProbeInput {
+        ProbeInput { pin, nets }
+    }
+
+    pub fn get_names(&self) -> Vec<&'static str> {
+        let mut names = self.nets.clone();
+        names.push(self.pin);
+        names
+    }
+
+    // The pin is only renamed if it is wired to a renamed net
+    pub fn rename(self, names: &Names) -> ProbeInput {
+        let rename = |name: &'static str| *names.get(name).unwrap_or(&name);
+        let pin = if self.nets.iter().any(|net| names.contains_key(net)) { rename(self.pin) } else { self.pin };
+
+        ProbeInput { pin, nets: self.nets.into_iter().map(rename).collect() }
+    }
+
+    // Both are wired to the same pin
+    pub fn merge(&mut self, other: ProbeInput) {
+        self.nets.extend(other.nets.into_iter().filter(|net| !self.nets.contains(net)).collect::<Vec<&str>>());
+    }
+
+    pub fn is_triggered(&self, contacts: &[Contact]) -> bool {
+        contacts.iter().any(|contact| match contact.nets {
+            Some((a, b)) => self.nets.contains(&a) || self.nets.contains(&b),
+            None => false,
+        })
+    }
 }
 
 impl Probe {
-    pub fn new(objects: Vec<(Transform, ShapeHandle<f64>)>) -> Probe {
+    pub fn new(objects: Vec<(&'static str, Material, Transform, ShapeHandle<f64>)>) -> Probe {
         Probe { objects }
     }
 
+    // Cylinders, cones and capsules are along the Y axis, like the procedural meshes
     pub fn get_cylinder_shape(diameter: f64, height: f64, transform: &Transform) -> (TriMesh<f32>, ShapeHandle<f64>) {
-        let mut mesh64 = ncollide3d::procedural::cylinder(diameter, height, 32);
-        let mut mesh32 = ncollide3d::procedural::cylinder(diameter as f32, height as f32, if diameter < 0.001 { 4 } else { 32 });
+        let mesh = ncollide3d::procedural::cylinder(diameter as f32, height as f32, if diameter < 0.001 { 4 } else { 32 });
 
-        mesh64.transform_by(transform);
-        mesh32.transform_by(&na::convert(*transform));
-
-        let shape = ConvexHull::try_from_points(&mesh64.coords.clone()).expect("Invalid convex shape");
-        shape.check_geometry();
-        
-        (mesh32, ShapeHandle::new(shape))
+        Probe::place(mesh, transform, Cylinder::new(height / 2.0, diameter / 2.0))
     }
 
     pub fn get_box_shape(x: f64, y: f64, z: f64, transform: &Transform) -> (TriMesh<f32>, ShapeHandle<f64>) {
-        let mut mesh64 = ncollide3d::procedural::cuboid(&Vec3::new(x, y, z));
-        let mut mesh32 = ncollide3d::procedural::cuboid(&na::Vector3::new(x as f32, y as f32, z as f32));
+        let mesh = ncollide3d::procedural::cuboid(&na::Vector3::new(x as f32, y as f32, z as f32));
+
+        Probe::place(mesh, transform, Cuboid::new(Vec3::new(x, y, z) / 2.0))
+    }
+
+    pub fn get_sphere_shape(diameter: f64, transform: &Transform) -> (TriMesh<f32>, ShapeHandle<f64>) {
+        let mesh = ncollide3d::procedural::sphere(diameter as f32, 32, 16, false);
+
+        Probe::place(mesh, transform, Ball::new(diameter / 2.0))
+    }
+
+    // The apex is at +Y
+    pub fn get_cone_shape(diameter: f64, height: f64, transform: &Transform) -> (TriMesh<f32>, ShapeHandle<f64>) {
+        let mesh = ncollide3d::procedural::cone(diameter as f32, height as f32, 32);
+
+        Probe::place(mesh, transform, Cone::new(height / 2.0, diameter / 2.0))
+    }
+
+    // `height` is the length of the cylindrical part, without the caps
+    pub fn get_capsule_shape(diameter: f64, height: f64, transform: &Transform) -> (TriMesh<f32>, ShapeHandle<f64>) {
+        let mesh = ncollide3d::procedural::capsule(&(diameter as f32), &(height as f32), 32, 16);
+
+        Probe::place(mesh, transform, Capsule::new(height / 2.0, diameter / 2.0))
+    }
+
+    // A triangle mesh is not convex, but it is hollow: shapes that are completely inside it
+    // do not touch it
+    pub fn get_mesh_shape(mesh: &MeshFile, transform: &Transform) -> ShapeHandle<f64> {
+        let vertices = mesh.vertices.iter().map(|v| transform * v).collect::<Vec<Point3<f64>>>();
+        let triangles = mesh.triangles.iter().cloned().filter(|t| {
+            (vertices[t.y] - vertices[t.x]).cross(&(vertices[t.z] - vertices[t.x])).norm() > 1e-15
+        }).collect();
 
-        mesh64.transform_by(transform);
-        mesh32.transform_by(&na::convert(*transform));
+        ShapeHandle::new(ncollide3d::shape::TriMesh::new(vertices, triangles, None))
+    }
+
+    // The names of all shapes and nets
+    pub fn get_names(&self) -> Vec<&'static str> {
+        self.objects.iter().flat_map(|(name, material, _, _)| match material {
+            Material::Insulating | Material::Conductive(None) => vec![*name],
+            Material::Conductive(Some(net)) | Material::Switch(net) => vec![*name, *net],
+        }).collect()
+    }
 
-        let shape = ConvexHull::try_from_points(&mesh64.coords.clone()).expect("Invalid convex shape");
-        shape.check_geometry();
-        
-        (mesh32, ShapeHandle::new(shape))
+    pub fn rename(mut self, names: &Names) -> Probe {
+        let rename = |name: &'static str| *names.get(name).unwrap_or(&name);
+
+        for (name, material, _, _) in self.objects.iter_mut() {
+            *name = rename(name);
+            *material = match *material {
+                Material::Conductive(Some(net)) => Material::Conductive(Some(rename(net))),
+                Material::Switch(net) => Material::Switch(rename(net)),
+                other => other,
+            };
+        }
+        self
     }
 
-    pub fn is_touching(&self, other: &Probe) -> bool {
-        for (a_transform, a_shape) in &self.objects {
-            for (b_transform, b_shape) in &other.objects {
-                if ncollide3d::query::proximity(a_transform, &**a_shape, b_transform, &**b_shape, 0.0) == ncollide3d::query::Proximity::Intersecting {
-                    return true;
+    // All shapes of both probes, for moving them together
+    pub fn join(mut self, other: Probe) -> Probe {
+        self.objects.extend(other.objects);
+        self
+    }
+
+    fn place<S: SupportMap<f64> + Clone + Send + Sync + 'static>(mut mesh: TriMesh<f32>, transform: &Transform, shape: S) -> (TriMesh<f32>, ShapeHandle<f64>) {
+        mesh.transform_by(&na::convert(*transform));
+
+        (mesh, ShapeHandle::new(PlacedShape { transform: *transform, shape }))
+    }
+
+    // A contact that closes the probe circuit
+    pub fn is_triggered(&self, other: &Probe) -> bool {
+        self.get_contacts(other).iter().any(|contact| contact.nets.is_some())
+    }
+
+    // All pairs of shapes that are touching
+    pub fn get_contacts(&self, other: &Probe) -> Vec<Contact> {
+        self.get_contacts_at(other, &Vec3::zeros(), 0.0)
+    }
+
+    pub fn distance_to(&self, other: &Probe) -> Option<Separation> {
+        let mut nearest: Option<Separation> = None;
+
+        for (a_name, _, a_transform, a_shape) in &self.objects {
+            for (b_name, _, b_transform, b_shape) in &other.objects {
+                let limit = nearest.as_ref().map_or(f64::INFINITY, |n| n.distance);
+
+                if let Some((distance, probe_point, object_point)) = get_closest_points(a_transform, a_shape, b_transform, b_shape, limit) {
+                    nearest = Some(Separation { probe_shape: a_name, object_shape: b_name, distance, probe_point, object_point });
                 }
             }
         }
 
-        false
+        nearest
     }
 
-    pub fn approx_time_of_impact(&self, other: &Probe, movement: &Vec3) -> f64 {
-        let mut smallest_toi = 1.0;
+    // Smallest distance between any pair of shapes, 0 when touching
+    fn distance(&self, other: &Probe, offset: &Vec3) -> f64 {
+        let offset = Transform::translation(offset.x, offset.y, offset.z);
+        let mut smallest = f64::INFINITY;
 
-        for (a_transform, a_shape) in &self.objects {
-            for (b_transform, b_shape) in &other.objects {
-                let toi = ncollide3d::query::time_of_impact(a_transform, movement, &**a_shape, b_transform, &Vec3::zeros(), &**b_shape, 1.0, 0.0);
+        for (_, _, a_transform, a_shape) in &self.objects {
+            for (_, _, b_transform, b_shape) in &other.objects {
+                let a_transform = offset * a_transform;
 
-                if let Some(time) = toi {
-                    if time.toi < smallest_toi {
-                        smallest_toi = time.toi;
-                    }
-                }
+                search_parts(&a_transform, a_shape, b_transform, b_shape, smallest, &mut |a_part, b_part| {
+                    smallest = smallest.min(ncollide3d::query::distance(&a_transform, a_part, b_transform, b_part));
+                    smallest
+                });
             }
         }
 
-        smallest_toi.max(0.0).min(1.0)
+        smallest.max(0.0)
     }
-/*
-    pub fn probe_towards(&self, other: &Probe, movement: &Vec3) -> Vec3 {
-        let mut result = movement.clone();
 
-        for (a_transform, a_shape) in &self.objects {
-            for (b_transform, b_shape) in &other.objects {
-                let toi = ncollide3d::query::time_of_impact(a_transform, movement, &**a_shape, b_transform, &Vec3::zeros(), &**b_shape, 1.0, 0.0);
+    fn is_touching_at(&self, other: &Probe, offset: &Vec3) -> bool {
+        let offset = Transform::translation(offset.x, offset.y, offset.z);
 
-                if let Some(toi) = toi {
-                    let mut delta = movement * toi.toi;
+        self.objects.iter().any(|(_, _, a_transform, a_shape)| other.objects.iter().any(|(_, _, b_transform, b_shape)| {
+            let a_transform = offset * a_transform;
+            let mut touching = false;
 
-                    for _ in 0..100 {
-                        if delta.norm() >= result.norm() {
-                            break;
-                        }
-                        if ncollide3d::query::proximity(&(a_transform * &Transform::translation(delta.x, delta.y, delta.z)), &**a_shape, 
-                                                        b_transform, &**b_shape, 0.0) == ncollide3d::query::Proximity::Intersecting {
-                            result = delta;
-                            break;
+            search_parts(&a_transform, a_shape, b_transform, b_shape, 0.0, &mut |a_part, b_part| {
+                touching = ncollide3d::query::proximity(&a_transform, a_part, b_transform, b_part, 0.0) == ncollide3d::query::Proximity::Intersecting;
+                if touching { -1.0 } else { 0.0 }
+            });
+
+            touching
+        }))
+    }
+
+    // Shapes closer than `prediction` are included as well, with a negative depth. There is
+    // one contact per pair of shapes, the deepest one.
+    fn get_contacts_at(&self, other: &Probe, offset: &Vec3, prediction: f64) -> Vec<Contact> {
+        let offset = Transform::translation(offset.x, offset.y, offset.z);
+        let mut contacts = Vec::new();
+
+        for (a_name, a_material, a_transform, a_shape) in &self.objects {
+            for (b_name, b_material, b_transform, b_shape) in &other.objects {
+                let a_transform = offset * a_transform;
+                let mut deepest: Option<ncollide3d::query::Contact<f64>> = None;
+
+                search_parts(&a_transform, a_shape, b_transform, b_shape, prediction, &mut |a_part, b_part| {
+                    if let Some(contact) = ncollide3d::query::contact(&a_transform, a_part, b_transform, b_part, prediction) {
+                        let is_deeper = match deepest {
+                            Some(d) => contact.depth > d.depth,
+                            None => true,
+                        };
+                        if is_deeper {
+                            deepest = Some(contact);
                         }
-                        delta += movement.normalize() * 1e-6;
                     }
+                    prediction
+                });
+
+                if let Some(contact) = deepest {
+                    contacts.push(Contact {
+                        probe_shape: a_name,
+                        object_shape: b_name,
+                        nets: a_material.connect(*b_material),
+                        point: contact.world2.coords,
+                        normal: contact.normal.into_inner(),
+                        depth: contact.depth,
+                    });
                 }
             }
         }
 
-        result
+        contacts
+    }
+
+    // Moves this probe along `movement` until it first touches `other`. Conservative advancement
+    // never steps further than the separation, so it can't pass through thin parts or into
+    // hollow meshes, and steps at least `tolerance` so it always gets to the end of the move.
+    // Bisection then finds the first touching position to within `tolerance`.
+    pub fn probe_towards(&self, other: &Probe, movement: &Vec3, tolerance: f64) -> Option<(f64, Contact)> {
+        let length = movement.norm();
+
+        if self.is_touching_at(other, &Vec3::zeros()) {
+            return Some((0.0, self.get_deepest_contact(other, &Vec3::zeros(), tolerance)));
+        }
+        if length == 0.0 {
+            return None;
+        }
+
+        // `lower` is never touching
+        let mut lower = 0.0;
+        let mut upper = loop {
+            let step = self.distance(other, &(movement * lower)).max(tolerance) / length;
+            let next = (lower + step).min(1.0);
+
+            if self.is_touching_at(other, &(movement * next)) {
+                break next;
+            }
+            if next >= 1.0 {
+                return None;
+            }
+            lower = next;
+        };
+
+        while (upper - lower) * length > tolerance {
+            let middle = (lower + upper) / 2.0;
+
+            if self.is_touching_at(other, &(movement * middle)) {
+                upper = middle;
+            } else {
+                lower = middle;
+            }
+        }
+
+        Some((upper, self.get_deepest_contact(other, &(movement * upper), tolerance)))
+    }
+
+    // Contacts that trigger the probe go before collisions
+    fn get_deepest_contact(&self, other: &Probe, offset: &Vec3, tolerance: f64) -> Contact {
+        let contacts = self.get_contacts_at(other, offset, tolerance);
+
+        match contacts.into_iter().max_by(|a, b| a.nets.is_some().cmp(&b.nets.is_some()).then(a.depth.partial_cmp(&b.depth).unwrap())) {
+            Some(contact) => contact,
+            // should not happen, the probes are touching
+            None => Contact {
+                probe_shape: "",
+                object_shape: "",
+                nets: None,
+                point: Vec3::zeros(),
+                normal: Vec3::zeros(),
+                depth: 0.0,
+            },
+        }
+    }
+}
+
+// A node in the bounding volume tree of a mesh, or a convex shape
+#[derive(Copy, Clone)]
+enum Part<'a> {
+    Mesh(&'a ncollide3d::shape::TriMesh<f64>, BVTNodeId),
+    Convex(&'a ShapeHandle<f64>),
+}
+
+impl<'a> Part<'a> {
+    fn new(shape: &'a ShapeHandle<f64>) -> Part<'a> {
+        match shape.as_shape::<ncollide3d::shape::TriMesh<f64>>() {
+            Some(mesh) => Part::Mesh(mesh, mesh.bvt().root().unwrap()),
+            None => Part::Convex(shape),
+        }
+    }
+
+    fn aabb(&self, transform: &Transform) -> AABB<f64> {
+        match self {
+            Part::Mesh(mesh, node) => mesh.bvt().content(*node).0.transform_by(transform),
+            Part::Convex(shape) => shape.aabb(transform),
+        }
+    }
+
+    fn is_leaf(&self) -> bool {
+        match self {
+            Part::Mesh(mesh, node) => mesh.bvt().num_children(*node) == 0,
+            Part::Convex(_) => true,
+        }
+    }
+
+    fn get_children(&self) -> Vec<Part<'a>> {
+        match self {
+            Part::Mesh(mesh, node) => (0..mesh.bvt().num_children(*node)).map(|i| Part::Mesh(mesh, mesh.bvt().child(i, *node))).collect(),
+            Part::Convex(_) => Vec::new(),
+        }
+    }
+
+    fn get_shape(&self) -> ShapeHandle<f64> {
+        match self {
+            Part::Mesh(mesh, node) => ShapeHandle::new(mesh.triangle_at(*mesh.bvt().content(*node).1.unwrap())),
+            Part::Convex(shape) => (*shape).clone(),
+        }
+    }
+}
+
+struct PartPair<'a> {
+    gap: f64,
+    a: Part<'a>,
+    b: Part<'a>,
+}
+
+impl<'a> PartialEq for PartPair<'a> {
+    fn eq(&self, other: &PartPair) -> bool {
+        self.gap == other.gap
+    }
+}
+
+impl<'a> Eq for PartPair<'a> {}
+
+impl<'a> PartialOrd for PartPair<'a> {
+    fn partial_cmp(&self, other: &PartPair) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// Reversed, so the heap returns the smallest gap first
+impl<'a> Ord for PartPair<'a> {
+    fn cmp(&self, other: &PartPair) -> Ordering {
+        other.gap.partial_cmp(&self.gap).unwrap_or(Ordering::Equal)
+    }
+}
+
+type PartVisitor<'a> = dyn FnMut(&dyn Shape<f64>, &dyn Shape<f64>) -> f64 + 'a;
+
+// Calls `visit` for the pairs of convex parts (triangles of meshes) that are at most `limit`
+// apart, nearest first, until the bounding boxes are further apart than the limit returned
+// by `visit`. ncollide compares every triangle of a mesh with the whole other shape, which
+// is much too slow for the meshes of the machine and the calibration objects.
+fn search_parts(a_transform: &Transform, a_shape: &ShapeHandle<f64>, b_transform: &Transform, b_shape: &ShapeHandle<f64>,
+                limit: f64, visit: &mut PartVisitor) {
+    // the gap between the bounding boxes, or between a convex part and a bounding box, which
+    // is much closer for long and thin triangles
+    let gap = |a: &Part, b: &Part| {
+        let (a_box, b_box) = (a.aabb(a_transform), b.aabb(b_transform));
+        let to_cuboid = |aabb: &AABB<f64>| (Transform::translation(aabb.center().x, aabb.center().y, aabb.center().z), Cuboid::new(aabb.half_extents()));
+
+        match (a.is_leaf(), b.is_leaf()) {
+            (true, false) => {
+                let (b_center, b_cuboid) = to_cuboid(&b_box);
+                ncollide3d::query::distance(a_transform, &*a.get_shape(), &b_center, &b_cuboid)
+            }
+            (false, true) => {
+                let (a_center, a_cuboid) = to_cuboid(&a_box);
+                ncollide3d::query::distance(&a_center, &a_cuboid, b_transform, &*b.get_shape())
+            }
+            _ => (a_box.mins() - b_box.maxs()).zip_map(&(b_box.mins() - a_box.maxs()), |x, y| x.max(y).max(0.0)).norm(),
+        }
+    };
+
+    let (a, b) = (Part::new(a_shape), Part::new(b_shape));
+    let mut limit = limit;
+    let mut heap = BinaryHeap::new();
+    heap.push(PartPair { gap: gap(&a, &b), a, b });
+
+    while let Some(PartPair { gap: pair_gap, a, b }) = heap.pop() {
+        if pair_gap > limit {
+            break;
+        }
+
+        if a.is_leaf() && b.is_leaf() {
+            limit = visit(&*a.get_shape(), &*b.get_shape());
+        } else if b.is_leaf() || (!a.is_leaf() && a.aabb(a_transform).half_extents().norm() > b.aabb(b_transform).half_extents().norm()) {
+            for a in a.get_children() {
+                heap.push(PartPair { gap: gap(&a, &b), a, b });
+            }
+        } else {
+            for b in b.get_children() {
+                heap.push(PartPair { gap: gap(&a, &b), a, b });
+            }
+        }
+    }
+}
+
+// Signed distance and closest points (or deepest points when penetrating) of two shapes, if
+// they are closer than `limit`
+fn get_closest_points(a_transform: &Transform, a_shape: &ShapeHandle<f64>, b_transform: &Transform, b_shape: &ShapeHandle<f64>, limit: f64) -> Option<(f64, Vec3, Vec3)> {
+    let mut nearest: Option<(f64, Vec3, Vec3)> = None;
+
+    search_parts(a_transform, a_shape, b_transform, b_shape, limit, &mut |a_part, b_part| {
+        let limit = nearest.map_or(limit, |n| n.0.max(0.0));
+        let closest = match ncollide3d::query::closest_points(a_transform, a_part, b_transform, b_part, limit) {
+            ClosestPoints::WithinMargin(a, b) => Some((na::distance(&a, &b), a.coords, b.coords)),
+            ClosestPoints::Intersecting => ncollide3d::query::contact(a_transform, a_part, b_transform, b_part, 0.0)
+                .map(|contact| (-contact.depth, contact.world1.coords, contact.world2.coords)),
+            ClosestPoints::Disjoint => None,
+        };
+
+        if let Some(closest) = closest {
+            let is_nearer = match nearest {
+                Some(n) => closest.0 < n.0,
+                None => true,
+            };
+            if is_nearer {
+                nearest = Some(closest);
+            }
+        }
+        nearest.map_or(limit, |n| n.0.max(0.0))
+    });
+
+    nearest.filter(|n| n.0 < limit)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOLERANCE: f64 = 1e-6;
+
+    // A 3 mm ball at the origin, like the tip of a ball end mill
+    fn get_ball() -> Probe {
+        let (_, ball) = Probe::get_sphere_shape(0.003, &Transform::identity());
+        Probe::new(vec![("ball", Material::Conductive(Some(PROBE_NET)), Transform::identity(), ball)])
+    }
+
+    fn get_box(name: &'static str, size: &Vec3, center: &Vec3) -> (&'static str, Material, Transform, ShapeHandle<f64>) {
+        let (_, shape) = Probe::get_box_shape(size.x, size.y, size.z, &Transform::identity());
+        (name, Material::Conductive(Some("object")), Transform::translation(center.x, center.y, center.z), shape)
+    }
+
+    // A 0.1 mm sheet with its top at 99.95 mm below the ball, and a thick block below it
+    fn get_sheet_and_block() -> Vec<(&'static str, Material, Transform, ShapeHandle<f64>)> {
+        vec![
+            get_box("sheet", &Vec3::new(0.02, 0.02, 0.0001), &Vec3::new(0.0, 0.0, -0.1)),
+            get_box("block", &Vec3::new(0.02, 0.02, 0.01), &Vec3::new(0.0, 0.0, -0.15)),
+        ]
+    }
+
+    fn assert_touches_sheet(result: Option<(f64, Contact)>, movement: &Vec3) {
+        let (toi, contact) = result.expect("the sheet was not found");
+        let expected = 0.1 - 0.00005 - 0.0015;
+
+        assert_eq!(contact.object_shape, "sheet");
+        assert!((toi * movement.norm() - expected).abs() <= 2.0 * TOLERANCE, "touched after {} instead of {}", toi * movement.norm(), expected);
+    }
+
+    #[test]
+    fn finds_thin_object_from_far_away() {
+        let movement = Vec3::new(0.0, 0.0, -0.2);
+        let object = Probe::new(get_sheet_and_block());
+
+        assert_touches_sheet(get_ball().probe_towards(&object, &movement, TOLERANCE), &movement);
+    }
+
+    #[test]
+    fn finds_thin_object_after_grazing_another() {
+        // the ball passes a rail at half the tolerance, which stops conservative advancement
+        let movement = Vec3::new(0.0, 0.0, -0.2);
+        let mut objects = get_sheet_and_block();
+        objects.push(get_box("rail", &Vec3::new(0.001, 0.01, 0.002), &Vec3::new(0.0015 + TOLERANCE / 2.0 + 0.0005, 0.0, -0.002)));
+
+        assert_touches_sheet(get_ball().probe_towards(&Probe::new(objects), &movement, TOLERANCE), &movement);
+    }
+
+    #[test]
+    fn misses_object_beyond_the_move() {
+        let object = Probe::new(get_sheet_and_block());
+
+        assert!(get_ball().probe_towards(&object, &Vec3::new(0.0, 0.0, -0.05), TOLERANCE).is_none());
     }
-*/
 }
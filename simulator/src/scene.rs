@@ -0,0 +1,200 @@
+use crate::calibration_object::{self, CalibrationObject, FeelerGauge, GaugeBlock, ObjectDefinition, ReferenceSphere, RingGauge, TwoWires};
+use crate::chain::Vec3;
+use crate::probe::{Names, Probe, ProbeInput, PROBE_NET};
+
+use kiss3d::window::Window;
+use serde::Deserialize;
+use std::fs;
+use std::path::Path;
+
+// Scene files use meters for lengths and degrees for angles, like machine files.
+
+#[derive(Deserialize)]
+pub struct SceneDefinition {
+    pub objects: Vec<SceneObjectDefinition>,
+}
+
+// Objects without a position are placed where the script expects them. `tilt` is around X and Y.
+#[derive(Deserialize)]
+pub struct SceneObjectDefinition {
+    pub name: Option<String>,
+    pub position: Option<[f64; 3]>,
+    #[serde(default)]
+    pub rotation: f64,
+    #[serde(default)]
+    pub tilt: [f64; 2],
+    #[serde(flatten)]
+    pub object: ObjectKindDefinition,
+}
+
+// Sizes are in meters as well
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum ObjectKindDefinition {
+    FeelerGauge {
+        #[serde(default = "default_gauge_size")]
+        size: [f64; 3],
+        #[serde(default)]
+        gauge_rotation: f64,
+        #[serde(default)]
+        gauge_tilt: [f64; 2],
+    },
+    TwoWires {
+        #[serde(default = "default_wire_diameter")]
+        wire_diameter: f64,
+    },
+    GaugeBlock {
+        #[serde(default = "default_block_size")]
+        size: [f64; 3],
+    },
+    ReferenceSphere {
+        #[serde(default = "default_sphere_diameter")]
+        diameter: f64,
+        #[serde(default = "default_stem_length")]
+        stem_length: f64,
+    },
+    RingGauge {
+        #[serde(default = "default_ring_inner_diameter")]
+        inner_diameter: f64,
+        #[serde(default = "default_ring_outer_diameter")]
+        outer_diameter: f64,
+        #[serde(default = "default_ring_height")]
+        height: f64,
+    },
+}
+
+// All calibration objects on the spoilboard. Objects with a name get it in front of the names
+// of their shapes, nets and probe inputs, so they can be told apart. Inputs with the same pin
+// are wired together, like z_min of all objects.
+pub struct Scene {
+    objects: Vec<(Box<dyn CalibrationObject>, Names)>,
+}
+
+fn default_gauge_size() -> [f64; 3] {
+    [0.089, 0.013, 0.0008]
+}
+
+fn default_wire_diameter() -> f64 {
+    0.0001
+}
+
+fn default_block_size() -> [f64; 3] {
+    [0.0762, 0.0508, 0.0254]
+}
+
+fn default_sphere_diameter() -> f64 {
+    0.025
+}
+
+fn default_stem_length() -> f64 {
+    0.010
+}
+
+fn default_ring_inner_diameter() -> f64 {
+    0.050
+}
+
+fn default_ring_outer_diameter() -> f64 {
+    0.080
+}
+
+fn default_ring_height() -> f64 {
+    0.020
+}
+
+impl SceneDefinition {
+    pub fn load(path: &Path) -> Result<SceneDefinition, String> {
+        let contents = fs::read_to_string(path).map_err(|e| format!("could not read {}: {}", path.display(), e))?;
+        let scene: SceneDefinition = serde_json::from_str(&contents).map_err(|e| format!("could not parse {}: {}", path.display(), e))?;
+
+        if scene.objects.is_empty() {
+            return Err(format!("{} has no objects", path.display()));
+        }
+        Ok(scene)
+    }
+
+    pub fn get_objects(&self) -> Vec<(Option<String>, ObjectDefinition)> {
+        self.objects.iter().map(|object| (object.name.clone(), object.build())).collect()
+    }
+}
+
+impl SceneObjectDefinition {
+    fn build(&self) -> ObjectDefinition {
+        let pose = |default: Vec3| calibration_object::get_pose(&self.position.map_or(default, Vec3::from),
+            self.rotation.to_radians(), self.tilt[0].to_radians(), self.tilt[1].to_radians());
+
+        match &self.object {
+            ObjectKindDefinition::FeelerGauge { size, gauge_rotation, gauge_tilt } => ObjectDefinition::FeelerGauge {
+                pos: pose(FeelerGauge::get_default_position()),
+                size: Vec3::from(*size),
+                tilt: calibration_object::get_pose(&Vec3::zeros(), gauge_rotation.to_radians(), gauge_tilt[0].to_radians(), gauge_tilt[1].to_radians()).rotation,
+            },
+            ObjectKindDefinition::TwoWires { wire_diameter } => ObjectDefinition::TwoWires {
+                pos: pose(TwoWires::get_default_position()),
+                wire_diameter: *wire_diameter,
+            },
+            ObjectKindDefinition::GaugeBlock { size } => ObjectDefinition::GaugeBlock {
+                pos: pose(GaugeBlock::get_default_position()),
+                size: Vec3::from(*size),
+            },
+            ObjectKindDefinition::ReferenceSphere { diameter, stem_length } => ObjectDefinition::ReferenceSphere {
+                pos: pose(ReferenceSphere::get_default_position()),
+                diameter: *diameter,
+                stem_length: *stem_length,
+            },
+            ObjectKindDefinition::RingGauge { inner_diameter, outer_diameter, height } => ObjectDefinition::RingGauge {
+                pos: pose(RingGauge::get_default_position()),
+                inner_diameter: *inner_diameter,
+                outer_diameter: *outer_diameter,
+                height: *height,
+            },
+        }
+    }
+}
+
+impl Scene {
+    pub fn new(window: &mut Window, resources_dir: &Path, objects: &[(Option<String>, ObjectDefinition)]) -> Box<dyn CalibrationObject> {
+        let objects = objects.iter().map(|(name, definition)| {
+            let object = definition.build(window, resources_dir);
+            let mut names = Names::new();
+
+            // the scene is built once, so the names can live as long as the program
+            if let Some(prefix) = name {
+                let inputs = object.get_inputs().iter().flat_map(|input| input.get_names()).collect::<Vec<&str>>();
+                for name in object.get_probe().get_names().into_iter().chain(inputs).filter(|name| *name != PROBE_NET) {
+                    names.entry(name).or_insert_with(|| Box::leak(format!("{}/{}", prefix, name).into_boxed_str()));
+                }
+            }
+
+            (object, names)
+        }).collect();
+
+        Box::new(Scene { objects })
+    }
+}
+
+impl CalibrationObject for Scene {
+    fn get_probe(&self) -> Probe {
+        self.objects.iter().map(|(object, names)| object.get_probe().rename(names))
+            .fold(Probe::new(Vec::new()), Probe::join)
+    }
+
+    fn render(&mut self) {
+        for (object, _) in self.objects.iter_mut() {
+            object.render();
+        }
+    }
+
+    fn get_inputs(&self) -> Vec<ProbeInput> {
+        let mut inputs: Vec<ProbeInput> = Vec::new();
+
+        for input in self.objects.iter().flat_map(|(object, names)| object.get_inputs().into_iter().map(move |input| input.rename(names))) {
+            match inputs.iter_mut().find(|existing| existing.pin == input.pin) {
+                Some(existing) => existing.merge(input),
+                None => inputs.push(input),
+            }
+        }
+
+        inputs
+    }
+}
@@ -0,0 +1,48 @@
+use crate::chain::{Transform, Vec3};
+use crate::probe::Contact;
+
+// The tool bends sideways like a cantilever until the contact force is large enough to close
+// the probe circuit. It is much stiffer along its axis, which is treated as rigid.
+#[derive(Copy, Clone)]
+pub struct Stylus {
+    stiffness: f64,
+    trigger_force: f64,
+}
+
+impl Stylus {
+    // The stiffness is sideways at the tip, in N/m
+    pub fn new(stiffness: f64, trigger_force: f64) -> Stylus {
+        Stylus {
+            stiffness,
+            trigger_force: trigger_force.max(0.0),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.stiffness.is_finite() && self.stiffness > 0.0 && self.trigger_force > 0.0
+    }
+
+    // The deflection of the tip when the probe triggers. The contact is frictionless, so the
+    // force is along the contact normal.
+    pub fn get_deflection(&self, contact: &Contact, tip: &Transform) -> Vec3 {
+        if !self.is_enabled() {
+            return Vec3::zeros();
+        }
+
+        let axis = tip.rotation * Vec3::z();
+        let sideways = contact.normal - axis * axis.dot(&contact.normal);
+        -sideways * self.trigger_force / self.stiffness
+    }
+
+    // How much further the tip moves along `movement` after the first contact until the probe
+    // triggers, as a fraction of `movement`. The tip stays on the surface, so the movement
+    // along the normal is taken up by the deflection.
+    pub fn get_overtravel(&self, contact: &Contact, tip: &Transform, movement: &Vec3) -> f64 {
+        let approach = contact.normal.dot(movement);
+
+        if approach <= 0.0 {
+            return 0.0;
+        }
+        -contact.normal.dot(&self.get_deflection(contact, tip)) / approach
+    }
+}
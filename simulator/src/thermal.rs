@@ -0,0 +1,46 @@
+use crate::mpcnc::Parameter;
+
+use enum_map::EnumMap;
+
+// The spindle warms up with a first order response: the drift approaches its
+// final value exponentially, with the given time constant.
+#[derive(Copy, Clone)]
+pub struct ThermalDrift {
+    z_growth: f64,
+    tilt_x: f64,
+    tilt_y: f64,
+    time_constant: f64,
+    spindle_driven: bool,
+}
+
+impl ThermalDrift {
+    pub fn new(z_growth: f64, tilt_x: f64, tilt_y: f64, time_constant: f64, spindle_driven: bool) -> ThermalDrift {
+        ThermalDrift {
+            z_growth,
+            tilt_x,
+            tilt_y,
+            time_constant: time_constant.max(1e-3),
+            spindle_driven,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.z_growth != 0.0 || self.tilt_x != 0.0 || self.tilt_y != 0.0
+    }
+
+    // Only count time while the spindle is on (M3/M4), instead of all elapsed time
+    pub fn is_spindle_driven(&self) -> bool {
+        self.spindle_driven
+    }
+
+    pub fn get_offsets(&self, heating_time: f64) -> EnumMap<Parameter, f64> {
+        let warmth = 1.0 - (-heating_time.max(0.0) / self.time_constant).exp();
+        let mut offsets = EnumMap::new();
+
+        // growth of the spindle pushes the tip down
+        offsets[Parameter::SpindleGrowth] = -self.z_growth * warmth;
+        offsets[Parameter::SpindleX] = self.tilt_x * warmth;
+        offsets[Parameter::SpindleY] = self.tilt_y * warmth;
+        offsets
+    }
+}
@@ -0,0 +1,132 @@
+use crate::chain::{Chain, Transform, Vec3};
+use crate::parameters::{Limits, ParameterId, Parameters};
+
+use std::collections::HashSet;
+use std::fmt::Write;
+use std::path::PathBuf;
+
+pub struct Visual {
+    pub file: PathBuf,
+    pub frame: usize,
+    pub scale: f32,
+    pub color: [f32; 3],
+}
+
+// Writes the chain as a URDF robot. The joints are at zero for the given parameters, so the
+// joint limits are relative to them. Screw links have no URDF equivalent and are exported
+// as fixed joints, and the stationary axes of meshes are ignored.
+pub fn export(name: &str, chain: &Chain, parameters: &Parameters, visuals: &[Visual]) -> String {
+    let mut urdf = String::new();
+    let frames = (0..=chain.get_links().len()).map(|index| chain.get_frame_name(index)).collect::<Vec<Option<&str>>>();
+
+    // generated names get a suffix when the machine description already uses them for a frame
+    let mut taken = frames.iter().flatten().map(|name| name.to_string()).collect::<HashSet<String>>();
+    let mut unique_name = |base: String| {
+        let (mut name, mut suffix) = (base.clone(), 2);
+        while taken.contains(&name) {
+            name = format!("{}_{}", base, suffix);
+            suffix += 1;
+        }
+        taken.insert(name.clone());
+        name
+    };
+    let link_names = frames.iter().enumerate()
+        .map(|(index, frame)| frame.map_or_else(|| unique_name(format!("link_{}", index)), |name| name.to_string()))
+        .collect::<Vec<String>>();
+
+    writeln!(urdf, "<?xml version=\"1.0\"?>").unwrap();
+    writeln!(urdf, "<robot name=\"{}\">", escape(name)).unwrap();
+
+    for (index, link_name) in link_names.iter().enumerate() {
+        writeln!(urdf, "  <link name=\"{}\">", escape(link_name)).unwrap();
+        for (i, visual) in visuals.iter().enumerate().filter(|(_, visual)| visual.frame == index) {
+            write_visual(&mut urdf, visual, &format!("material_{}", i));
+        }
+        writeln!(urdf, "  </link>").unwrap();
+    }
+
+    for (i, link) in chain.get_links().iter().enumerate() {
+        let parent = &link_names[i];
+        let child = &link_names[i + 1];
+        let local = link.get_local_transform(parameters);
+
+        match link.get_local_twist() {
+            Some((param, linear, angular)) if angular.norm() == 0.0 => {
+                let axis = local.rotation.inverse() * linear;
+                // one parameter can drive several links, so the name includes the link
+                let name = format!("joint_{}_{}", i + 1, chain.get_registry().get(param).name);
+
+                write_joint(&mut urdf, &name, "prismatic", parent, child, &local, Some(&axis));
+                write_limits(&mut urdf, chain, parameters, param);
+                writeln!(urdf, "  </joint>").unwrap();
+            }
+            Some((param, linear, angular)) if linear.dot(&angular).abs() < 1e-12 => {
+                // the twist of a rotation around an axis through `pivot` is (pivot x axis, axis)
+                let pivot = angular.cross(&linear) / angular.norm_squared();
+                let axis = local.rotation.inverse() * angular;
+                let info = chain.get_registry().get(param);
+                let kind = if let Limits::Range(_, _) = info.limits { "revolute" } else { "continuous" };
+                let pivot_link = if pivot.norm() == 0.0 { child.clone() } else { unique_name(format!("{}_pivot", child)) };
+
+                write_joint(&mut urdf, &format!("joint_{}_{}", i + 1, info.name), kind, parent, &pivot_link, &Transform::from_parts(pivot.into(), local.rotation), Some(&axis));
+                write_limits(&mut urdf, chain, parameters, param);
+                writeln!(urdf, "  </joint>").unwrap();
+
+                if pivot_link != *child {
+                    writeln!(urdf, "  <link name=\"{}\"/>", escape(&pivot_link)).unwrap();
+                    write_joint(&mut urdf, &format!("joint_{}", i + 1), "fixed", &pivot_link, child, &Transform::translation(-pivot.x, -pivot.y, -pivot.z), None);
+                    writeln!(urdf, "  </joint>").unwrap();
+                }
+            }
+            _ => {
+                write_joint(&mut urdf, &format!("joint_{}", i + 1), "fixed", parent, child, &local, None);
+                writeln!(urdf, "  </joint>").unwrap();
+            }
+        }
+    }
+
+    writeln!(urdf, "</robot>").unwrap();
+    urdf
+}
+
+// Materials are shared by name between links, so each visual gets its own
+fn write_visual(urdf: &mut String, visual: &Visual, material: &str) {
+    writeln!(urdf, "    <visual>").unwrap();
+    writeln!(urdf, "      <geometry>").unwrap();
+    writeln!(urdf, "        <mesh filename=\"file://{}\" scale=\"{} {} {}\"/>", escape(&visual.file.display().to_string()), visual.scale, visual.scale, visual.scale).unwrap();
+    writeln!(urdf, "      </geometry>").unwrap();
+    writeln!(urdf, "      <material name=\"{}\">", material).unwrap();
+    writeln!(urdf, "        <color rgba=\"{} {} {} 1\"/>", visual.color[0], visual.color[1], visual.color[2]).unwrap();
+    writeln!(urdf, "      </material>").unwrap();
+    writeln!(urdf, "    </visual>").unwrap();
+}
+
+// Leaves the joint element open, so limits can be added
+fn write_joint(urdf: &mut String, name: &str, kind: &str, parent: &str, child: &str, origin: &Transform, axis: Option<&Vec3>) {
+    let (roll, pitch, yaw) = origin.rotation.euler_angles();
+    let xyz = origin.translation.vector;
+
+    writeln!(urdf, "  <joint name=\"{}\" type=\"{}\">", escape(name), kind).unwrap();
+    writeln!(urdf, "    <parent link=\"{}\"/>", escape(parent)).unwrap();
+    writeln!(urdf, "    <child link=\"{}\"/>", escape(child)).unwrap();
+    writeln!(urdf, "    <origin xyz=\"{} {} {}\" rpy=\"{} {} {}\"/>", xyz.x, xyz.y, xyz.z, roll, pitch, yaw).unwrap();
+    if let Some(axis) = axis {
+        writeln!(urdf, "    <axis xyz=\"{} {} {}\"/>", axis.x, axis.y, axis.z).unwrap();
+    }
+}
+
+fn write_limits(urdf: &mut String, chain: &Chain, parameters: &Parameters, param: ParameterId) {
+    let value = parameters[param];
+    let (lower, upper) = match chain.get_registry().get(param).limits {
+        Limits::Range(min, max) => (min - value, max - value),
+        // continuous joints ignore the limits, but a prismatic joint needs them
+        _ => (-1000.0, 1000.0),
+    };
+
+    writeln!(urdf, "    <limit lower=\"{}\" upper=\"{}\" effort=\"0\" velocity=\"0\"/>", lower, upper).unwrap();
+}
+
+// Names come from the machine description and can contain any character
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;").replace('\'', "&apos;")
+}